@@ -0,0 +1,237 @@
+use crate::state::Index;
+use crate::{Error, Result};
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read};
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continues the CRC-32 (IEEE) `crc` of the bytes before `bytes`. Starts from 0.
+pub fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ crc >> 8;
+    }
+    !crc
+}
+
+/// Appends `n` to `buf` as a LEB128 varint, seven bits per byte, low bits first.
+pub fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+pub fn read_varint<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut n = 0_u64;
+    let mut byte = [0_u8; 1];
+    for shift in (0..64).step_by(7) {
+        reader.read_exact(&mut byte[..])?;
+        n |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(invalid("varint longer than 64 bits"))
+}
+
+/// Encodes an index snapshot: the version and the number of entries, then every entry as its
+/// key length, key, offset, length and checksum, all numbers as varints.
+pub fn encode_indexes(version: u64, indexes: &BTreeMap<String, Index>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(indexes.len() * 16);
+    write_varint(&mut buf, version);
+    write_varint(&mut buf, indexes.len() as u64);
+    for (key, index) in indexes.iter() {
+        write_key(&mut buf, key);
+        write_index(&mut buf, index);
+    }
+    buf
+}
+
+/// Decodes what [`encode_indexes`] wrote, one entry at a time.
+pub fn decode_indexes<R: Read>(mut reader: R) -> Result<(u64, BTreeMap<String, Index>)> {
+    let version = read_varint(&mut reader)?;
+    let count = read_varint(&mut reader)?;
+    let mut indexes = BTreeMap::new();
+    for _ in 0..count {
+        let key = read_key(&mut reader)?;
+        indexes.insert(key, read_index(&mut reader)?);
+    }
+    Ok((version, indexes))
+}
+
+/// Encodes the entries changed since the last index record: the version and the number of
+/// entries, then every entry as its key and either a zero tombstone or a one followed by its
+/// offset, length and checksum.
+pub fn encode_delta<'a, I>(version: u64, entries: I) -> Vec<u8>
+where
+    I: ExactSizeIterator<Item = (&'a str, Option<&'a Index>)>,
+{
+    let mut buf = vec![];
+    write_varint(&mut buf, version);
+    write_varint(&mut buf, entries.len() as u64);
+    for (key, index) in entries {
+        write_key(&mut buf, key);
+        match index {
+            Some(index) => {
+                buf.push(1);
+                write_index(&mut buf, index);
+            }
+            None => buf.push(0),
+        }
+    }
+    buf
+}
+
+/// The version of a delta and its entries, with `None` for removed keys.
+pub type Delta = (u64, Vec<(String, Option<Index>)>);
+
+/// Decodes what [`encode_delta`] wrote.
+pub fn decode_delta<R: Read>(mut reader: R) -> Result<Delta> {
+    let version = read_varint(&mut reader)?;
+    let count = read_varint(&mut reader)?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let key = read_key(&mut reader)?;
+        let mut tag = [0_u8; 1];
+        reader.read_exact(&mut tag[..])?;
+        let index = match tag[0] {
+            0 => None,
+            1 => Some(read_index(&mut reader)?),
+            _ => return Err(invalid("unknown delta entry")),
+        };
+        entries.push((key, index));
+    }
+    Ok((version, entries))
+}
+
+/// Writes the checksum shifted up by one, leaving zero for values stored without one.
+fn write_index(buf: &mut Vec<u8>, index: &Index) {
+    write_varint(buf, index.offset);
+    write_varint(buf, index.length);
+    write_varint(
+        buf,
+        index.checksum.map_or(0, |checksum| checksum as u64 + 1),
+    );
+}
+
+fn read_index<R: Read>(reader: &mut R) -> Result<Index> {
+    let offset = read_varint(reader)?;
+    let length = read_varint(reader)?;
+    let checksum = match read_varint(reader)? {
+        0 => None,
+        n => Some(u32::try_from(n - 1).map_err(|_| invalid("checksum out of range"))?),
+    };
+    Ok(Index {
+        offset,
+        length,
+        checksum,
+    })
+}
+
+fn write_key(buf: &mut Vec<u8>, key: &str) {
+    write_varint(buf, key.len() as u64);
+    buf.extend_from_slice(key.as_bytes());
+}
+
+fn read_key<R: Read>(reader: &mut R) -> Result<String> {
+    let len = read_varint(reader)?;
+    let mut key = vec![];
+    reader.take(len).read_to_end(&mut key)?;
+    if key.len() as u64 != len {
+        return Err(Error::IO(ErrorKind::UnexpectedEof.into()));
+    }
+    String::from_utf8(key).map_err(|_| invalid("key is not valid UTF-8"))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::IO(std::io::Error::new(ErrorKind::InvalidData, message))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codec::{
+        crc32, decode_delta, decode_indexes, encode_delta, encode_indexes, read_varint,
+        write_varint,
+    };
+    use crate::state::Index;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_varint() {
+        let mut buf = vec![];
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            write_varint(&mut buf, n);
+        }
+        assert_eq!(buf[..4], [0, 1, 127, 0x80]);
+        let mut reader = &buf[..];
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            assert_eq!(read_varint(&mut reader).unwrap(), n);
+        }
+        assert!(read_varint(&mut &[0xff_u8; 10][..]).is_err());
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_indexes() {
+        let indexes: BTreeMap<String, Index> = (0..1000_u64)
+            .map(|i| {
+                let index = Index {
+                    offset: i * 1024 + 1,
+                    length: i,
+                    checksum: (i % 2 == 0).then_some(i as u32),
+                };
+                (format!("key{i}"), index)
+            })
+            .collect();
+        let encoded = encode_indexes(7, &indexes);
+        let json = serde_json::to_vec(&indexes).unwrap();
+        assert!(encoded.len() * 2 < json.len());
+        let (version, decoded) = decode_indexes(&encoded[..]).unwrap();
+        assert_eq!(version, 7);
+        assert_eq!(decoded.len(), indexes.len());
+        assert_eq!(decoded["key999"].offset, 999 * 1024 + 1);
+        assert_eq!(decoded["key998"].checksum, Some(998));
+        assert_eq!(decoded["key999"].checksum, None);
+        assert!(decode_indexes(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_delta() {
+        let index = Index {
+            offset: 5000,
+            length: 3,
+            checksum: Some(u32::MAX),
+        };
+        let entries = [("a", Some(&index)), ("b", None)];
+        let encoded = encode_delta(9, entries.into_iter());
+        let (version, decoded) = decode_delta(&encoded[..]).unwrap();
+        assert_eq!(version, 9);
+        assert_eq!(decoded[0].1.as_ref().unwrap().offset, 5000);
+        assert_eq!(decoded[0].1.as_ref().unwrap().checksum, Some(u32::MAX));
+        assert_eq!(decoded[1].0, "b");
+        assert!(decoded[1].1.is_none());
+    }
+}
@@ -1,110 +1,1033 @@
-use crate::lock::Lock;
+use crate::hook::{Hooks, TransactionView};
+use crate::ivec::IVec;
+use crate::lock::{KeyLocks, Lock, LockMode, WaitGraph};
 use crate::lru_map::LruMap;
-use crate::state::{PublicState, State, StateBuilder};
-use crate::transaction::{TransactionBatch, TransactionBatchBuilder};
-use crate::tree::{TransactionTrees, Tree};
-use crate::Result;
-use spin::mutex::Mutex;
+use crate::monitor::{
+    prepared_statuses, statuses, ActiveTransaction, ActiveTransactions, TransactionStatus,
+    Watchdog, WatchdogHandle,
+};
+use crate::secondary::SecondaryIndex;
+use crate::snapshot::{
+    Archive, HistoricalSnapshot, History, ReadTransaction, Retention, SnapshotTree,
+};
+use crate::state::{
+    IndexRecord, PublicState, State, StateBuilder, StateWriter, TornRecord, VersionedState,
+};
+use crate::thread_pool::ThreadPool;
+use crate::transaction::{
+    GroupCommit, LogRecords, TransactionBatch, TransactionBatchBuilder, TransactionData,
+    TransactionWriter, TreeWrites, WriteSet, PAGE_LEN,
+};
+use crate::tree::{
+    CommitInfo, LockGranularity, PreparedTransaction, Savepoints, SecondaryBinding,
+    TransactionMode, TransactionOptions, TransactionTrees, TransactionUsage, Tree,
+};
+use crate::{Error, Result};
+use spin::mutex::{Mutex, MutexGuard};
 use spin::rwlock::RwLock;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, DerefMut};
 
-use std::path::Path;
-use std::sync::atomic::AtomicBool;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 pub const TRANSACTION_FILE: &str = "db.transaction";
-pub type Cache = LruMap<usize, Vec<u8>, 1024>;
+/// The commits compaction moved out of the log, see [`History`].
+pub const HISTORY_FILE: &str = "db.history";
+pub type Cache = LruMap<usize, IVec, 1024>;
 
 pub struct Db {
     pub file_manager: FileManager,
     pub context: Context,
     pub states: RwLock<HashMap<String, PublicState>>,
+    pub indexes: RwLock<HashMap<String, Arc<SecondaryIndex>>>,
+    /// Held exclusively while a commit publishes its trees, so that snapshots never see part of
+    /// a commit.
+    pub publish: RwLock<()>,
+    /// Stopped before `batch`, whose log thread it sends to.
+    pub watchdog: Option<WatchdogHandle>,
     pub batch: TransactionBatch,
+    /// Source of the ids transactions hold key locks under.
+    pub owners: AtomicUsize,
+    /// Which transactions wait for which, over the key locks of all trees.
+    pub waits: Arc<WaitGraph>,
+    /// The options of transactions started without options of their own.
+    pub defaults: RwLock<TransactionOptions>,
+    pub hooks: RwLock<Hooks>,
+    /// Runs the post-commit hooks of the commits nobody waits for.
+    pub post_commit: Arc<ThreadPool>,
+    pub retention: Retention,
+    /// The commits of the log, read as far as [`Db::snapshot_at`] needed.
+    pub history: Mutex<History>,
+    /// Tree states rebuilt for historical snapshots, by tree and version.
+    pub past_states: Mutex<LruMap<(String, u64), Arc<VersionedState>, 16>>,
+    /// Transactions prepared by [`TransactionTrees::prepare`], by id.
+    pub prepared: Mutex<HashMap<usize, PreparedTransaction>>,
+    /// The running transactions, by owner. Resumed prepared transactions are left out.
+    pub active: ActiveTransactions,
+    pub torn_tails: TornTails,
+    /// The torn records found in the files opened so far.
+    pub torn: Mutex<Vec<TornRecord>>,
 }
 
 impl Db {
     pub fn new() -> Result<Self> {
-        let file_manager = FileManager::new();
+        Self::open(".")
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(path, Config::default())
+    }
+
+    pub fn open_with<P: AsRef<Path>>(path: P, config: Config) -> Result<Self> {
+        std::fs::create_dir_all(path.as_ref())?;
+        let file_manager = FileManager::new(path.as_ref().to_path_buf());
+        let history = History::open(&file_manager.dir.join(HISTORY_FILE))
+            .map_err(|err| err.in_file(HISTORY_FILE))?;
         let file = file_manager.get_or_insert(TRANSACTION_FILE)?;
-        let mut transaction_builder = TransactionBatchBuilder { file };
-        let batch = transaction_builder.build()?;
+        let mut transaction_builder = TransactionBatchBuilder {
+            file,
+            group_commit: config.group_commit,
+        };
+        let mut recovery = transaction_builder
+            .recover()
+            .map_err(|err| err.in_file(TRANSACTION_FILE))?;
+        let mut torn = vec![];
+        if let Some(mut record) = recovery.torn.take() {
+            record.file = TRANSACTION_FILE.to_owned();
+            if config.torn_tails == TornTails::Truncate {
+                record.truncate(transaction_builder.file.write().deref_mut())?;
+            }
+            torn.push(record);
+        }
+        let mut redo: HashMap<String, Vec<TreeWrites>> = HashMap::new();
+        for (_, tree) in recovery.writes {
+            redo.entry(tree.name.clone()).or_default().push(tree);
+        }
+        let batch = transaction_builder.start(recovery.transaction_id)?;
+        let waits = Arc::new(WaitGraph::default());
+        let active = ActiveTransactions::default();
+        let watchdog = config.watchdog.map(|watchdog| {
+            let log = batch.sender.clone().unwrap();
+            watchdog.start(active.clone(), waits.clone(), log)
+        });
 
         let this = Self {
             file_manager,
             context: Context {},
             states: RwLock::new(HashMap::new()),
+            indexes: RwLock::new(HashMap::new()),
+            publish: RwLock::new(()),
+            watchdog,
             batch,
+            owners: AtomicUsize::new(0),
+            waits,
+            defaults: RwLock::new(TransactionOptions::default()),
+            hooks: RwLock::new(Hooks::default()),
+            post_commit: Arc::new(ThreadPool::new(1)),
+            retention: config.retention,
+            history: Mutex::new(history),
+            past_states: Mutex::new(LruMap::new()),
+            prepared: Mutex::new(HashMap::new()),
+            active,
+            torn_tails: config.torn_tails,
+            torn: Mutex::new(torn),
         };
+        // every tree the log wrote to catches up now, so nothing of the log is kept around
+        for (name, writes) in redo {
+            this.open_state(&name, writes)?;
+        }
+        for (transaction_id, write_set) in recovery.in_doubt {
+            this.restore_prepared(transaction_id, write_set)?;
+        }
+        // a kept torn record would end up in the middle of the log
+        if this
+            .torn
+            .lock()
+            .iter()
+            .all(|record| record.file != TRANSACTION_FILE || record.truncated)
+        {
+            this.compact()?;
+        }
         Ok(this)
     }
 
-    pub fn open_tree(&self, name: &str) -> Result<Tree> {
-        let mut guard = self.states.write();
-        let state = guard
-            .entry(name.to_owned())
-            .or_insert({
-                let file_name = FileManager::file_name(name);
-                let file = self.file_manager.get_or_insert(file_name.as_str())?;
-                let state_builder = StateBuilder { file };
-                let version_state = state_builder.build()?;
-                PublicState {
-                    cache: Arc::new(RwLock::new(Cache::new())),
-                    lock: Arc::new(Lock::new()),
-                    reader: Arc::new(RwLock::new(version_state)),
+    /// Rewrites the log without the records whose writes the index records of the tree files
+    /// hold, keeping the commits they belong to in [`HISTORY_FILE`], then drops from the tree
+    /// files the versions before the [`Retention`] horizon.
+    fn compact(&self) -> Result<()> {
+        let history = self.history()?;
+        let horizon = self.retention.horizon(history.commits.len());
+        // the last index record of each tree at its version at the horizon or before, which
+        // stands in for the versions before it
+        let mut floors = HashMap::new();
+        if horizon > 0 {
+            for (name, version) in history.versions_at(horizon - 1) {
+                let public = self.public_state(&name)?;
+                let builder = StateBuilder {
+                    file: public.file.clone(),
+                };
+                let (floor, _) = builder
+                    .recover_at(version)
+                    .map_err(|err| err.in_file(&public.file_name))?;
+                floors.insert(name, floor);
+            }
+        }
+        // the logged versions of each tree with an index record of their own
+        let mut recorded = HashMap::new();
+        for (name, positions) in history.positions.iter() {
+            let Some((first, _)) = positions.first_key_value() else {
+                continue;
+            };
+            let public = self.public_state(name)?;
+            let builder = StateBuilder {
+                file: public.file.clone(),
+            };
+            let records = builder
+                .records_after(first - 1)
+                .map_err(|err| err.in_file(&public.file_name))?;
+            // the index records standing in for the dropped writes reach the disk first
+            public.file.read().sync_data()?;
+            let versions: HashSet<u64> = records.iter().map(IndexRecord::version).collect();
+            recorded.insert(name.as_str(), versions);
+        }
+        let mut kept = HashSet::new();
+        let mut logged = HashSet::new();
+        for (name, positions) in history.positions.iter() {
+            for (version, position) in positions.iter() {
+                let covered = recorded
+                    .get(name.as_str())
+                    .is_some_and(|versions| versions.contains(version))
+                    || floors.get(name).is_some_and(|floor| version <= floor);
+                if !covered {
+                    kept.insert(*position);
+                    logged.insert(name.clone());
                 }
-            })
-            .clone();
-        // drop(guard);
+            }
+        }
+        let mut dropped: HashSet<u64> = history
+            .positions
+            .values()
+            .flat_map(|positions| positions.values().copied())
+            .filter(|position| !kept.contains(position))
+            .collect();
+        dropped.extend(history.discarded.iter().copied());
+        for (first, second) in history.decided.iter() {
+            if dropped.contains(first) {
+                dropped.insert(*second);
+            }
+        }
+        // the trees prepared transactions still in doubt write to keep their records too
+        for commit in history.prepared.values() {
+            logged.extend(commit.versions.iter().map(|(name, _)| name.clone()));
+        }
+        let archive = history.archive(horizon);
+        drop(history);
+
+        let log = self.file_manager.get_or_insert(TRANSACTION_FILE)?;
+        let mut builder = TransactionBatchBuilder {
+            file: log.clone(),
+            group_commit: GroupCommit::default(),
+        };
+        let LogRecords { records, end, .. } = builder
+            .records_from(0)
+            .map_err(|err| err.in_file(TRANSACTION_FILE))?;
+        // ids are never handed out twice, so the largest one stays in the log
+        let last_id = records
+            .iter()
+            .map(|(_, record)| record.transaction_id)
+            .max()
+            .unwrap_or(0);
+        let mut id_kept = false;
+        for (position, record) in records.iter() {
+            if record.data.is_none() {
+                if record.transaction_id < last_id || id_kept {
+                    dropped.insert(*position);
+                }
+                id_kept |= record.transaction_id == last_id;
+            }
+        }
+        let rewrite = records
+            .iter()
+            .any(|(position, _)| dropped.contains(position));
+        if rewrite || horizon > 0 {
+            self.write_history(&archive)?;
+            if rewrite {
+                self.rewrite_log(&log, &records, end, &dropped, last_id)?;
+            }
+            *self.history.lock() = History::open(&self.file_manager.dir.join(HISTORY_FILE))?;
+        }
+
+        for (name, floor) in floors {
+            let torn =
+                self.torn.lock().iter().any(|record| {
+                    record.file == FileManager::file_name(&name) && !record.truncated
+                });
+            if floor > 0 && !logged.contains(&name) && !torn {
+                self.compact_tree(&name, floor)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces [`HISTORY_FILE`] with `archive`.
+    fn write_history(&self, archive: &Archive) -> Result<()> {
+        let path = self.file_manager.dir.join(HISTORY_FILE);
+        let compacted = self
+            .file_manager
+            .dir
+            .join(format!("{HISTORY_FILE}.compact"));
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&compacted)?;
+        out.write_all(&serde_json::to_vec(archive)?[..])?;
+        out.sync_all()?;
+        std::fs::rename(&compacted, &path)?;
+        self.file_manager.created.store(true, Ordering::SeqCst);
+        self.file_manager.sync_dir()
+    }
+
+    /// Replaces the log with its `records` but the `dropped` ones, and a record of `last_id`
+    /// unless a kept one holds it.
+    fn rewrite_log(
+        &self,
+        log: &Arc<RwLock<File>>,
+        records: &[(u64, TransactionData)],
+        end: u64,
+        dropped: &HashSet<u64>,
+        last_id: usize,
+    ) -> Result<()> {
+        let path = self.file_manager.dir.join(TRANSACTION_FILE);
+        let compacted = self
+            .file_manager
+            .dir
+            .join(format!("{TRANSACTION_FILE}.compact"));
+        let mut out = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(true)
+            .open(&compacted)?;
+        let mut file = log.write();
+        let mut last_kept = 0;
+        for (idx, (position, record)) in records.iter().enumerate() {
+            if dropped.contains(position) {
+                continue;
+            }
+            last_kept = last_kept.max(record.transaction_id);
+            // the record, its continuation pages and any padding up to the next one
+            let next = records.get(idx + 1).map_or(end, |(next, _)| *next);
+            let mut bytes = vec![0_u8; (next - position) as usize];
+            file.seek(SeekFrom::Start(*position))?;
+            file.read_exact(&mut bytes[..])?;
+            let len = out.metadata()?.len().div_ceil(PAGE_LEN) * PAGE_LEN;
+            out.set_len(len)?;
+            out.seek(SeekFrom::Start(len))?;
+            out.write_all(&bytes[..])?;
+        }
+        if last_id > last_kept {
+            let mut writer = TransactionWriter {
+                file: &mut out,
+                transaction_id: last_id,
+                data: None,
+            };
+            writer.write()?;
+        }
+        out.sync_all()?;
+        std::fs::rename(&compacted, &path)?;
+        self.file_manager.created.store(true, Ordering::SeqCst);
+        self.file_manager.sync_dir()?;
+        *file = OpenOptions::new().read(true).append(true).open(&path)?;
+        Ok(())
+    }
+
+    /// Rewrites the file of the tree `name` from the last index record at `floor` or before,
+    /// unless it has no records before that one. No logged write may point into the file, as
+    /// the values it keeps move.
+    fn compact_tree(&self, name: &str, floor: u64) -> Result<()> {
+        let public = self.public_state(name)?;
+        let builder = StateBuilder {
+            file: public.file.clone(),
+        };
+        let first = builder
+            .first_version()
+            .map_err(|err| err.in_file(&public.file_name))?;
+        if first.is_none_or(|first| first >= floor) {
+            return Ok(());
+        }
+        let path = self.file_manager.dir.join(public.file_name.as_str());
+        let compacted = self
+            .file_manager
+            .dir
+            .join(format!("{}.compact", public.file_name));
+        let mut out = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(true)
+            .open(&compacted)?;
+        builder
+            .compact(floor, &mut out, public.file_name.clone())
+            .map_err(|err| err.in_file(&public.file_name))?;
+        out.sync_all()?;
+        let mut file = public.file.write();
+        std::fs::rename(&compacted, &path)?;
+        self.file_manager.created.store(true, Ordering::SeqCst);
+        self.file_manager.sync_dir()?;
+        *file = OpenOptions::new().read(true).append(true).open(&path)?;
+        drop(file);
+        // the published state points into the old file, so the tree is opened again from the
+        // new one when next asked for
+        self.states.write().remove(name);
+        Ok(())
+    }
+
+    /// Takes the locks of a transaction found prepared in the log back, and gives it its writes
+    /// again, so that it can be committed or rolled back as if the database had never closed.
+    /// Fails with [`Error::PreparedLocks`] if a tree is locked already, releasing the locks
+    /// taken.
+    fn restore_prepared(&self, transaction_id: usize, write_set: WriteSet) -> Result<()> {
+        let owner = self.owners.fetch_add(1, Ordering::SeqCst);
+        let mut trees = vec![];
+        let relocked = self.relock_prepared(transaction_id, owner, write_set.trees, &mut trees);
+        if let Err(err) = relocked {
+            for tree in trees.iter() {
+                tree.state.public.keys.release(owner);
+                tree.state.public.lock.unlock();
+            }
+            return Err(err);
+        }
+        let prepared = PreparedTransaction {
+            locks: trees
+                .iter()
+                .map(|tree| tree.state.public.lock.clone())
+                .collect(),
+            trees,
+            owner,
+            options: write_set.options.unwrap_or_default(),
+            started: Instant::now(),
+        };
+        self.prepared.lock().insert(transaction_id, prepared);
+        Ok(())
+    }
+
+    /// Locks the trees of `writes` for `owner` and gives them the writes, adding each tree
+    /// locked to `trees`.
+    fn relock_prepared(
+        &self,
+        transaction_id: usize,
+        owner: usize,
+        writes: Vec<TreeWrites>,
+        trees: &mut Vec<Tree>,
+    ) -> Result<()> {
+        for writes in writes {
+            let public = self.public_state(writes.name.as_str())?;
+            // nothing else runs yet, so only another prepared transaction can hold the locks
+            let range = (Bound::Unbounded, Bound::Unbounded);
+            if !public.keys.try_lock(owner, range, LockMode::Exclusive) {
+                return Err(Error::PreparedLocks(transaction_id, writes.name));
+            }
+            if !public.lock.try_lock_for(owner) {
+                public.keys.release(owner);
+                return Err(Error::PreparedLocks(transaction_id, writes.name));
+            }
+            let state = State::new(public);
+            let mut writer = state.writer.lock();
+            for (key, index) in writes.writes {
+                match index {
+                    Some(index) => writer.insert(key, index),
+                    None => {
+                        writer.remove(key.as_str());
+                    }
+                }
+            }
+            drop(writer);
+            trees.push(Tree {
+                state,
+                name: Arc::new(writes.name),
+            });
+        }
+        Ok(())
+    }
+
+    /// The records found torn at the end of the files opened so far, see [`TornTails`]. Tree
+    /// files are opened along with their trees.
+    pub fn torn_records(&self) -> Vec<TornRecord> {
+        self.torn.lock().clone()
+    }
+
+    /// Cuts the kept torn records off their files, as [`TornTails::Truncate`] does on open, so
+    /// that the files can be written to again. A record followed by pages other than its own is
+    /// left alone.
+    pub fn truncate_torn(&self) -> Result<()> {
+        let mut torn = self.torn.lock();
+        for record in torn.iter_mut().filter(|record| !record.truncated) {
+            let file = self.file_manager.get_or_insert(record.file.as_str())?;
+            record.truncate(file.write().deref_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Fails with [`Error::TornRecord`] if `file` ends with a kept torn record.
+    pub(crate) fn check_writable(&self, file: &str) -> Result<()> {
+        let torn = self.torn.lock();
+        match torn
+            .iter()
+            .any(|record| record.file == file && !record.truncated)
+        {
+            true => Err(Error::TornRecord(file.to_owned())),
+            false => Ok(()),
+        }
+    }
+
+    pub fn open_tree(&self, name: &str) -> Result<Tree> {
+        let state = self.public_state(name)?;
         Ok(Tree {
-            state: State {
-                public: state.clone(),
-                writer: Mutex::new(state.reader.clone().read().clone()),
-            },
+            state: State::new(state),
             name: Arc::new(name.to_owned()),
         })
     }
 
-    pub fn start_transaction<I>(&self, names: I) -> Result<TransactionTrees>
+    pub(crate) fn public_state(&self, name: &str) -> Result<PublicState> {
+        self.open_state(name, vec![])
+    }
+
+    /// Opens the tree `name` unless it is open already, replaying the logged writes of `redo`
+    /// that its file does not have yet.
+    fn open_state(&self, name: &str, mut redo: Vec<TreeWrites>) -> Result<PublicState> {
+        let guard = self.states.upgradeable_read();
+        if let Some(state) = guard.get(name) {
+            return Ok(state.clone());
+        }
+        let mut guard = guard.upgrade();
+        let file_name = FileManager::file_name(name);
+        let file = self.file_manager.get_or_insert(file_name.as_str())?;
+        let state_builder = StateBuilder { file: file.clone() };
+        let (mut version_state, mut index_log, torn) = state_builder
+            .build_with_log()
+            .map_err(|err| err.in_file(&file_name))?;
+        let mut writable = true;
+        if let Some(mut record) = torn {
+            record.file = file_name.clone();
+            if self.torn_tails == TornTails::Truncate {
+                record.truncate(file.write().deref_mut())?;
+            }
+            writable = record.truncated;
+            self.torn.lock().push(record);
+        }
+        redo.sort_by_key(|writes| writes.version);
+        let mut removals = BTreeMap::new();
+        for writes in redo.iter() {
+            // versions up to the snapshot are in it already, and nothing after a missing version
+            // can be applied on top of it
+            if writes.version <= version_state.version {
+                continue;
+            }
+            if writes.version != version_state.version + 1 {
+                break;
+            }
+            version_state.apply(writes);
+            let removed = writes.removed();
+            if !removed.is_empty() {
+                removals.insert(writes.version, removed);
+            }
+            // with a record of its own, the version no longer needs the log, see `Db::compact`
+            if writable {
+                index_log.changed(writes);
+                let mut file = file.write();
+                let mut page_writer = StateWriter {
+                    file: file.deref_mut(),
+                    state: &version_state,
+                    log: &mut index_log,
+                };
+                page_writer.write().map_err(|err| err.in_file(&file_name))?;
+            }
+        }
+        let state = PublicState {
+            cache: Arc::new(RwLock::new(Cache::new())),
+            lock: Arc::new(Lock::new()),
+            keys: Arc::new(KeyLocks::new(self.waits.clone())),
+            reader: Arc::new(RwLock::new(Arc::new(version_state))),
+            file,
+            index_log: Arc::new(Mutex::new(index_log)),
+            file_name: Arc::new(file_name),
+            pins: Arc::new(Mutex::new(BTreeMap::new())),
+            removals: Arc::new(Mutex::new(removals)),
+            poisoned: Arc::new(Mutex::new(None)),
+        };
+        guard.insert(name.to_owned(), state.clone());
+        Ok(state)
+    }
+
+    pub fn start_transaction<I, S>(&self, names: I) -> Result<TransactionTrees<'_>>
     where
-        I: Iterator<Item = &'static str>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
     {
-        let trees: Result<Vec<Tree>> = names.map(|name| self.open_tree(name)).collect();
-        let trees = trees?;
-        let mut locks: Vec<_> = trees
-            .iter()
-            .map(|x| (x.name.clone(), x.state.public.lock.clone()))
+        self.start_transaction_with(names, self.defaults.read().clone())
+    }
+
+    pub fn start_transaction_with<I, S>(
+        &self,
+        names: I,
+        options: TransactionOptions,
+    ) -> Result<TransactionTrees<'_>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let names = names
+            .into_iter()
+            .map(|name| name.as_ref().to_owned())
             .collect();
-        locks.sort_by_key(|(name, _)| name.clone());
-        Ok(TransactionTrees {
+        self.begin(names, options)
+    }
+
+    fn begin(
+        &self,
+        mut names: Vec<String>,
+        options: TransactionOptions,
+    ) -> Result<TransactionTrees<'_>> {
+        // secondary indexes of the requested trees join the transaction after them
+        let mut secondary = vec![];
+        for index in self.indexes_of(names.iter()) {
+            let primary = names.iter().position(|name| *name == index.tree).unwrap();
+            let tree_name = index.tree_name();
+            let tree = match names.iter().position(|name| *name == tree_name) {
+                Some(tree) => tree,
+                None => {
+                    names.push(tree_name);
+                    names.len() - 1
+                }
+            };
+            secondary.push(SecondaryBinding {
+                primary,
+                tree,
+                index,
+            });
+        }
+        let states: Result<Vec<PublicState>> =
+            names.iter().map(|name| self.public_state(name)).collect();
+        let states = states?;
+        let owner = self.owners.fetch_add(1, Ordering::SeqCst);
+        let active = Arc::new(ActiveTransaction::new(
+            owner,
+            options.mode,
+            options.granularity,
+        ));
+        *active.trees.lock() = names.iter().cloned().zip(states.iter().cloned()).collect();
+        self.active.lock().insert(owner, active.clone());
+        let trees = names
+            .into_iter()
+            .zip(states)
+            .map(|(name, state)| Tree {
+                state: match options.mode {
+                    TransactionMode::Optimistic => State::pinned(state),
+                    TransactionMode::Pessimistic => State::new(state),
+                },
+                name: Arc::new(name),
+            })
+            .collect();
+        let trees = TransactionTrees {
             trees,
-            locks: locks
-                .into_iter()
-                .map(|(_, lock)| {
-                    lock.lock()?;
-                    Ok(lock)
-                })
-                .collect::<Result<Vec<Arc<Lock>>>>()?,
+            secondary,
+            options,
+            owner,
+            committed: AtomicBool::new(false),
+            db: self,
+            active,
+            savepoints: Mutex::new(Savepoints::default()),
+            prepared: None,
+            usage: Mutex::new(TransactionUsage::default()),
+            alive: Arc::new(()),
+        };
+        // the others only lock the trees while committing
+        if trees.tree_locked() {
+            trees.lock_from(0, None)?;
+            trees.id();
+        }
+        Ok(trees)
+    }
+
+    /// Runs `f` in a transaction over the trees `names`. The transaction commits when `f`
+    /// returns `Ok` and rolls back when it returns `Err`. After a conflict, `f` runs again in a
+    /// new transaction, as it does when `f` failed to add a tree out of lock order; the tree is
+    /// then part of the transaction from the start.
+    pub fn transaction<F, T, S>(&self, names: &[S], f: F) -> Result<T>
+    where
+        F: FnMut(&mut TransactionTrees<'_>) -> Result<T>,
+        S: AsRef<str>,
+    {
+        self.transaction_with(names, self.defaults.read().clone(), f)
+    }
+
+    /// Like [`Db::transaction`], running `f` again at most `options.retries` times.
+    pub fn transaction_with<F, T, S>(
+        &self,
+        names: &[S],
+        options: TransactionOptions,
+        mut f: F,
+    ) -> Result<T>
+    where
+        F: FnMut(&mut TransactionTrees<'_>) -> Result<T>,
+        S: AsRef<str>,
+    {
+        let mut names: Vec<String> = names.iter().map(|name| name.as_ref().to_owned()).collect();
+        let mut retries = 0;
+        loop {
+            let mut trees = self.begin(names.clone(), options.clone())?;
+            let result = f(&mut trees).and_then(|value| {
+                trees.commit()?;
+                Ok(value)
+            });
+            match result {
+                Ok(value) => return Ok(value),
+                Err(err) => {
+                    // the error that ended the attempt is the one to report, not a failure to
+                    // clean up after it
+                    if !trees.committed.load(Ordering::SeqCst) {
+                        let _ = trees.rollback();
+                    }
+                    if !err.is_retryable() || retries == options.retries {
+                        return Err(err);
+                    }
+                    if let Error::LockOrder(name) = &err {
+                        names.push(name.clone());
+                    }
+                    retries += 1;
+                }
+            }
+        }
+    }
+
+    /// Starts a read-only transaction over a consistent snapshot of the trees. No tree lock is
+    /// taken: writers keep committing while the snapshot is read, and the snapshot never sees
+    /// their changes.
+    pub fn read_transaction<I, S>(&self, names: I) -> Result<ReadTransaction<'_>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let states: Result<Vec<(String, PublicState)>> = names
+            .into_iter()
+            .map(|name| {
+                let name = name.as_ref();
+                Ok((name.to_owned(), self.public_state(name)?))
+            })
+            .collect();
+        let states = states?;
+        let guard = self.publish.read();
+        let trees = states
+            .into_iter()
+            .map(|(name, public)| SnapshotTree {
+                name,
+                state: public.snapshot(),
+                public,
+            })
+            .collect();
+        drop(guard);
+        Ok(ReadTransaction { trees, db: self })
+    }
+
+    /// Commits the prepared transaction with id `transaction_id`. Its writes become visible at
+    /// the id of the commit record, which the returned [`CommitInfo`] carries.
+    pub fn commit_prepared(&self, transaction_id: usize) -> Result<CommitInfo> {
+        self.resume(transaction_id)?.commit()
+    }
+
+    /// Rolls the prepared transaction with id `transaction_id` back and releases its locks.
+    pub fn rollback_prepared(&self, transaction_id: usize) -> Result<()> {
+        self.resume(transaction_id)?.rollback()
+    }
+
+    /// Describes the running and the prepared transactions, oldest first: their ids, age,
+    /// trees, the locks they hold and who they wait for.
+    pub fn active_transactions(&self) -> Vec<TransactionStatus> {
+        let mut statuses = statuses(&self.active, &self.waits);
+        statuses.extend(prepared_statuses(&self.prepared.lock()));
+        statuses.sort_by_key(|status| std::cmp::Reverse(status.age));
+        statuses
+    }
+
+    /// Aborts the transaction of `owner`, releasing its locks. Its later writes and its commit
+    /// fail with [`Error::Aborted`]. Returns `false` if the transaction is unknown or already
+    /// committing.
+    pub fn abort_transaction(&self, owner: usize) -> bool {
+        let transaction = self.active.lock().get(&owner).cloned();
+        transaction.is_some_and(|transaction| {
+            transaction.abort(&self.waits, |id| {
+                let _ = self.batch.drop(id);
+            })
+        })
+    }
+
+    /// The ids of the prepared transactions waiting to be committed or rolled back, including
+    /// those found in the log on open.
+    pub fn in_doubt(&self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.prepared.lock().keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Turns a prepared transaction back into one that can be finished. It holds the locks of
+    /// all its trees, like a transaction locking whole trees.
+    fn resume(&self, transaction_id: usize) -> Result<TransactionTrees<'_>> {
+        let prepared = self
+            .prepared
+            .lock()
+            .remove(&transaction_id)
+            .ok_or(Error::UnknownTransaction(transaction_id))?;
+        let active = ActiveTransaction::new(
+            prepared.owner,
+            TransactionMode::Pessimistic,
+            LockGranularity::Tree,
+        );
+        *active.locks.lock() = prepared.locks;
+        Ok(TransactionTrees {
+            trees: prepared.trees,
+            secondary: vec![],
+            options: TransactionOptions {
+                mode: TransactionMode::Pessimistic,
+                granularity: LockGranularity::Tree,
+                ..prepared.options
+            },
+            owner: prepared.owner,
             committed: AtomicBool::new(false),
             db: self,
-            transaction_id: self.batch.new_id(),
+            active: Arc::new(active),
+            savepoints: Mutex::new(Savepoints::default()),
+            prepared: Some(transaction_id),
+            usage: Mutex::new(TransactionUsage::default()),
+            alive: Arc::new(()),
+        })
+    }
+
+    /// Reads the trees as they were right after the transaction with id `transaction_id`
+    /// committed, along with every commit logged before it. Transactions get their ids when
+    /// they start, so commits logged before may well have larger ids. Fails with
+    /// [`Error::HistoryUnavailable`] for transactions that did not commit, or whose commit the
+    /// [`Retention`] policy no longer keeps.
+    pub fn snapshot_at(&self, transaction_id: usize) -> Result<HistoricalSnapshot<'_>> {
+        let history = self.history()?;
+        let horizon = self.retention.horizon(history.commits.len());
+        let idx = history.commits[horizon..]
+            .iter()
+            .rposition(|commit| commit.transaction_id == transaction_id)
+            .ok_or(Error::HistoryUnavailable(transaction_id))?;
+        Ok(HistoricalSnapshot {
+            transaction_id,
+            versions: history.versions_at(horizon + idx),
+            db: self,
         })
     }
+
+    /// The id of the oldest commit [`Db::snapshot_at`] still serves, or the next id to be handed
+    /// out if there is none.
+    pub fn retained_from(&self) -> Result<usize> {
+        let history = self.history()?;
+        let horizon = self.retention.horizon(history.commits.len());
+        Ok(match history.commits.get(horizon) {
+            Some(commit) => commit.transaction_id,
+            None => self.batch.transaction_id.load(Ordering::SeqCst),
+        })
+    }
+
+    /// The history of commits, caught up with the log.
+    fn history(&self) -> Result<MutexGuard<'_, History>> {
+        let mut builder = TransactionBatchBuilder {
+            file: self.file_manager.get_or_insert(TRANSACTION_FILE)?,
+            group_commit: GroupCommit::default(),
+        };
+        let mut history = self.history.lock();
+        history
+            .catch_up(&mut builder)
+            .map_err(|err| err.in_file(TRANSACTION_FILE))?;
+        Ok(history)
+    }
+
+    /// The state of the tree `name` at `version`: the published one if it is still at that
+    /// version, and otherwise the last index record at the version or before, with the logged
+    /// writes after it applied. `None` if the log no longer has all of those writes.
+    pub(crate) fn state_at(
+        &self,
+        name: &str,
+        public: &PublicState,
+        version: u64,
+    ) -> Result<Option<Arc<VersionedState>>> {
+        let current = public.snapshot();
+        if current.version == version {
+            return Ok(Some(current));
+        }
+        let key = (name.to_owned(), version);
+        if let Some(state) = self.past_states.lock().get(&key) {
+            return Ok(Some(state.clone()));
+        }
+        let builder = StateBuilder {
+            file: public.file.clone(),
+        };
+        let mut state = builder
+            .build_at(version)
+            .map_err(|err| err.in_file(&public.file_name))?;
+        let positions: Vec<u64> = match self.history()?.positions.get(name) {
+            Some(positions) if state.version < version => positions
+                .range(state.version + 1..=version)
+                .map(|(_, position)| *position)
+                .collect(),
+            _ => vec![],
+        };
+        if positions.len() as u64 != version - state.version {
+            return Ok(None);
+        }
+        let mut log = TransactionBatchBuilder {
+            file: self.file_manager.get_or_insert(TRANSACTION_FILE)?,
+            group_commit: GroupCommit::default(),
+        };
+        for position in positions {
+            let record = log
+                .record_at(position)
+                .map_err(|err| err.in_file(TRANSACTION_FILE))?;
+            let write_set: WriteSet = serde_json::from_slice(&record.data.unwrap_or_default())?;
+            for writes in write_set.trees.iter().filter(|writes| writes.name == name) {
+                state.apply(writes);
+            }
+        }
+        let state = Arc::new(state);
+        self.past_states.lock().insert(key, state.clone());
+        Ok(Some(state))
+    }
+
+    /// Declares a secondary index called `name` over `tree`. `extractor` maps a key and its value
+    /// to the index key, or `None` to leave the entry out of the index. It is always handed the
+    /// full value, so streamed values are read back into memory on indexed trees, see
+    /// [`IndexedTransactionTrees::put_stream`](crate::tree::IndexedTransactionTrees::put_stream).
+    /// Indexes are not persisted and have to be declared again after the database is reopened;
+    /// entries written while the index was not declared are picked up by [`Db::rebuild_index`].
+    pub fn define_index<F>(&self, name: &str, tree: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        let index = SecondaryIndex {
+            name: name.to_owned(),
+            tree: tree.to_owned(),
+            extractor: Arc::new(extractor),
+        };
+        self.indexes
+            .write()
+            .insert(name.to_owned(), Arc::new(index));
+        Ok(())
+    }
+
+    /// Throws away the content of the index tree and fills it again from the primary tree.
+    pub fn rebuild_index(&self, name: &str) -> Result<()> {
+        let index = self
+            .indexes
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::UnknownIndex(name.to_owned()))?;
+        let trees = self.begin(vec![index.tree.clone()], self.defaults.read().clone())?;
+        trees.rebuild_index(name)?;
+        trees.commit()?;
+        Ok(())
+    }
+
+    /// Registers a hook run before every commit that writes to `tree`, or before every commit
+    /// at all without a tree. The hook gets a read-only view of the transaction along with its
+    /// write set and can veto the commit by returning an error, [`Error::Rejected`] for
+    /// instance.
+    pub fn add_pre_commit_hook<F>(&self, tree: Option<&str>, hook: F)
+    where
+        F: Fn(&TransactionView, &WriteSet) -> Result<()> + Send + Sync + 'static,
+    {
+        self.hooks
+            .write()
+            .pre_commit
+            .push((tree.map(str::to_owned), Arc::new(hook)));
+    }
+
+    /// Registers a hook called with the transaction id once a commit that writes to `tree`, or
+    /// any commit without a tree, is done. The hooks of a commit waited for have run when the
+    /// wait returns; those of any other commit run on [`Db::post_commit`].
+    pub fn add_post_commit_hook<F>(&self, tree: Option<&str>, hook: F)
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.hooks
+            .write()
+            .post_commit
+            .push((tree.map(str::to_owned), Arc::new(hook)));
+    }
+
+    pub(crate) fn indexes_of<'a, I>(&self, trees: I) -> Vec<Arc<SecondaryIndex>>
+    where
+        I: Iterator<Item = &'a String>,
+    {
+        let guard = self.indexes.read();
+        let mut indexes: Vec<_> = trees
+            .flat_map(|tree| guard.values().filter(move |index| index.tree == *tree))
+            .cloned()
+            .collect();
+        indexes.sort_by(|a, b| a.name.cmp(&b.name));
+        indexes
+    }
 }
 
 pub struct Context {}
 
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub group_commit: GroupCommit,
+    pub retention: Retention,
+    pub watchdog: Option<Watchdog>,
+    pub torn_tails: TornTails,
+}
+
+/// What opening a file does with a record at its end that was cut short, as a crash in the
+/// middle of writing it leaves behind. Either way the file is read up to the record before, and
+/// the record is reported by [`Db::torn_records`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TornTails {
+    /// Leave the file as it is, to look into it before repairing it with
+    /// [`Db::truncate_torn`]. A record written after a kept one could no longer be told from
+    /// corruption, so writes fail with [`Error::TornRecord`] until then.
+    #[default]
+    Keep,
+    /// Cut the record off the file, unless something was appended after it.
+    Truncate,
+}
+
 pub struct FileManager {
+    pub dir: PathBuf,
     pub files: RwLock<HashMap<String, Arc<RwLock<File>>>>,
+    /// Set when a file was created since the directory was last synced.
+    pub created: AtomicBool,
 }
 
 impl FileManager {
-    pub fn new() -> Self {
+    pub fn new(dir: PathBuf) -> Self {
         Self {
+            dir,
             files: RwLock::new(HashMap::new()),
+            created: AtomicBool::new(false),
         }
     }
 
+    /// Syncs the directory, making the entries of the files created since the last sync
+    /// durable.
+    pub fn sync_dir(&self) -> Result<()> {
+        if self.created.swap(false, Ordering::SeqCst) {
+            #[cfg(unix)]
+            File::open(&self.dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
     #[inline]
     pub fn file_name(name: &str) -> String {
         format!("{}.tree", name)
@@ -113,19 +1036,17 @@ impl FileManager {
     pub fn get_or_insert(&self, name: &str) -> Result<Arc<RwLock<File>>> {
         let files_guard = self.files.upgradeable_read();
         let file = {
-            let path = Path::new(name);
+            let path = self.dir.join(name);
             if path.exists() && path.is_file() {
-                OpenOptions::new()
-                    .write(true)
-                    .read(true)
-                    .append(true)
-                    .open(name)
+                OpenOptions::new().read(true).append(true).open(path)
             } else {
+                self.created.store(true, Ordering::SeqCst);
                 OpenOptions::new()
                     .write(true)
                     .read(true)
                     .create(true)
-                    .open(name)
+                    .truncate(false)
+                    .open(path)
             }
         }?;
         if let Some(result) = files_guard.get(name) {
@@ -138,3 +1059,201 @@ impl FileManager {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::db::{Config, Db, TornTails, TRANSACTION_FILE};
+    use crate::error::{CorruptionKind, Error};
+    use crate::transaction::PAGE_LEN;
+    use crate::tree::{TransactionMode, TransactionOptions};
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_transaction_closure() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let count = db
+            .transaction(&["a", "b"], |tx| {
+                tx.tree("a").unwrap().set("k", "1")?;
+                tx.get(1).set("k", "2")?;
+                Ok(2)
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+
+        let result: crate::Result<()> = db.transaction(&["a"], |tx| {
+            tx.get(0).set("k", "lost")?;
+            Err(Error::Unknown("abort".to_owned()))
+        });
+        assert!(matches!(result, Err(Error::Unknown(_))));
+
+        // a conflicting commit sneaks in during the first two attempts
+        let options = TransactionOptions {
+            mode: TransactionMode::Optimistic,
+            retries: 2,
+            ..Default::default()
+        };
+        let mut attempts = 0;
+        let value = db
+            .transaction_with(&["a"], options.clone(), |tx| {
+                attempts += 1;
+                let value = tx.get(0).get("k")?.unwrap();
+                if attempts < 3 {
+                    db.transaction(&["a"], |tx| tx.get(0).set("k", format!("{attempts}")))?;
+                }
+                tx.get(0).set("k", "done")?;
+                Ok(value)
+            })
+            .unwrap();
+        assert_eq!((attempts, value), (3, "2".into()));
+
+        let result = db.transaction_with(&["a"], options, |tx| {
+            tx.get(0).get("k")?;
+            db.transaction(&["a"], |tx| tx.get(0).set("k", "again"))?;
+            tx.get(0).set("k", "never")
+        });
+        assert!(matches!(result, Err(Error::Conflict(_))));
+
+        db.transaction(&["a", "b"], |tx| {
+            assert_eq!(tx.get(0).get("k")?, Some("again".into()));
+            assert_eq!(tx.tree("b").unwrap().get("k")?, Some("2".into()));
+            assert!(tx.tree("c").is_none());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_two_phase_commit() {
+        let dir = tempdir().unwrap();
+        let busy = TransactionOptions {
+            lock_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let trees = db.start_transaction(["a"]).unwrap();
+            trees.get(0).set("k", "v1").unwrap();
+            let id = trees.prepare().unwrap();
+            assert_eq!(db.in_doubt(), vec![id]);
+            // the locks stay with the prepared transaction
+            assert!(matches!(
+                db.start_transaction_with(["a"], busy.clone()),
+                Err(Error::LockTimeout)
+            ));
+            let snapshot = db.read_transaction(["a"]).unwrap();
+            assert_eq!(snapshot.get(0).get("k").unwrap(), None);
+            db.commit_prepared(id).unwrap();
+            assert!(db.in_doubt().is_empty());
+            let snapshot = db.read_transaction(["a"]).unwrap();
+            assert_eq!(snapshot.get(0).get("k").unwrap(), Some("v1".into()));
+
+            let trees = db.start_transaction(["a"]).unwrap();
+            trees.get(0).set("k", "v2").unwrap();
+            trees.prepare().unwrap();
+            let trees = db.start_transaction_with(["b"], busy.clone()).unwrap();
+            trees.get(0).set("k", "w1").unwrap();
+            trees.prepare().unwrap();
+        }
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let in_doubt = db.in_doubt();
+            assert_eq!(in_doubt.len(), 2);
+            // the options are logged with the prepared transaction
+            let timeouts: Vec<_> = in_doubt
+                .iter()
+                .map(|id| db.prepared.lock()[id].options.lock_timeout)
+                .collect();
+            assert_eq!(timeouts, vec![None, busy.lock_timeout]);
+            assert!(matches!(
+                db.start_transaction_with(["a"], busy),
+                Err(Error::LockTimeout)
+            ));
+            db.rollback_prepared(in_doubt[0]).unwrap();
+            db.commit_prepared(in_doubt[1]).unwrap();
+            assert!(matches!(
+                db.commit_prepared(in_doubt[1]),
+                Err(Error::UnknownTransaction(_))
+            ));
+        }
+        let db = Db::open(dir.path()).unwrap();
+        assert!(db.in_doubt().is_empty());
+        let snapshot = db.read_transaction(["a", "b"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("v1".into()));
+        assert_eq!(snapshot.get(1).get("k").unwrap(), Some("w1".into()));
+    }
+
+    #[test]
+    fn test_torn_tails() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        db.transaction(&["a"], |tx| tx.get(0).set("k", "v1"))
+            .unwrap();
+        drop(db);
+        // records whose headers promise more than made it to the disk
+        let tear = |name: &str| {
+            let path = dir.path().join(name);
+            let mut file = OpenOptions::new().write(true).open(path).unwrap();
+            let offset = file.metadata().unwrap().len().div_ceil(PAGE_LEN) * PAGE_LEN;
+            file.seek(SeekFrom::Start(offset)).unwrap();
+            file.write_all(&[3_u8]).unwrap();
+            file.write_all(&[0xff_u8; 20]).unwrap();
+            offset
+        };
+        let offsets = [tear(TRANSACTION_FILE), tear("a.tree")];
+        let len = |name: &str| dir.path().join(name).metadata().unwrap().len();
+
+        // kept by default, refusing writes until they are cut off
+        let db = Db::open(dir.path()).unwrap();
+        let snapshot = db.read_transaction(["a"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("v1".into()));
+        drop(snapshot);
+        let torn = db.torn_records();
+        assert_eq!(torn.len(), 2);
+        assert_eq!(torn[0].file, TRANSACTION_FILE);
+        assert_eq!(torn[1].file, "a.tree");
+        assert!(torn.iter().all(|record| !record.truncated));
+        assert_eq!(len("a.tree"), offsets[1] + 21);
+        let written = db.transaction(&["a"], |tx| tx.get(0).set("k", "v2"));
+        assert!(matches!(written, Err(Error::TornRecord(file)) if file == TRANSACTION_FILE));
+        db.truncate_torn().unwrap();
+        let torn = db.torn_records();
+        for (record, offset) in torn.iter().zip(offsets) {
+            assert_eq!(
+                (record.offset, record.kind),
+                (offset, CorruptionKind::Truncated)
+            );
+            assert!(record.truncated);
+        }
+        assert_eq!(len(TRANSACTION_FILE), offsets[0]);
+        assert_eq!(len("a.tree"), offsets[1]);
+        db.transaction(&["a"], |tx| tx.get(0).set("k", "v2"))
+            .unwrap();
+        drop(db);
+
+        let offsets = [tear(TRANSACTION_FILE), tear("a.tree")];
+        let truncate = Config {
+            torn_tails: TornTails::Truncate,
+            ..Default::default()
+        };
+        let db = Db::open_with(dir.path(), truncate).unwrap();
+        let snapshot = db.read_transaction(["a"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("v2".into()));
+        drop(snapshot);
+        assert!(db.torn_records().iter().all(|record| record.truncated));
+        // the log was compacted after the record was cut off
+        assert!(len(TRANSACTION_FILE) <= offsets[0]);
+        assert_eq!(len("a.tree"), offsets[1]);
+        db.transaction(&["a"], |tx| tx.get(0).set("k", "v3"))
+            .unwrap();
+        drop(db);
+
+        let db = Db::open(dir.path()).unwrap();
+        let snapshot = db.read_transaction(["a"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("v3".into()));
+        assert!(db.torn_records().is_empty());
+    }
+}
@@ -12,4 +12,84 @@ pub enum Error {
     Unknown(String),
     #[error("Serde Error: {0}")]
     Serde(#[from] serde_json::error::Error),
+    #[error("Unknown Index: {0}")]
+    UnknownIndex(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Tree {0} added out of lock order")]
+    LockOrder(String),
+    #[error("Unknown Savepoint")]
+    UnknownSavepoint,
+    #[error("Lock Timeout")]
+    LockTimeout,
+    #[error("Deadlock")]
+    Deadlock,
+    #[error("Commit Rejected: {0}")]
+    Rejected(String),
+    #[error("History Unavailable: {0}")]
+    HistoryUnavailable(usize),
+    #[error("Unknown Transaction: {0}")]
+    UnknownTransaction(usize),
+    #[error("Prepared transaction {0} cannot take back its locks on tree {1}")]
+    PreparedLocks(usize, String),
+    #[error("Transaction Limit Exceeded: {0}")]
+    LimitExceeded(String),
+    #[error("Transaction Aborted")]
+    Aborted,
+    #[error("Transaction id {0} was reaped")]
+    Reaped(usize),
+    #[error("Tree file {0} is missing index records: {1}")]
+    Poisoned(String, String),
+    #[error("File {0} ends with a torn record")]
+    TornRecord(String),
+    #[error("Corruption in {file} at {offset}: {kind}")]
+    Corruption {
+        file: String,
+        offset: u64,
+        kind: CorruptionKind,
+    },
+}
+
+/// What was found wrong with a page or record.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum CorruptionKind {
+    #[error("checksum mismatch")]
+    Checksum,
+    #[error("unexpected page type {0}")]
+    PageType(u8),
+    #[error("record runs past the end of the file")]
+    Truncated,
+    #[error("undecodable record")]
+    Encoding,
+}
+
+impl Error {
+    /// A corruption found at `offset` of a file the caller names with [`Error::in_file`].
+    pub(crate) fn corruption(offset: u64, kind: CorruptionKind) -> Self {
+        Error::Corruption {
+            file: String::new(),
+            offset,
+            kind,
+        }
+    }
+
+    /// Names the file of a corruption error that does not say yet.
+    pub(crate) fn in_file(self, name: &str) -> Self {
+        match self {
+            Error::Corruption { file, offset, kind } if file.is_empty() => Error::Corruption {
+                file: name.to_owned(),
+                offset,
+                kind,
+            },
+            err => err,
+        }
+    }
+
+    /// Whether running the transaction again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::Conflict(_) | Error::LockOrder(_) | Error::LockTimeout | Error::Deadlock
+        )
+    }
 }
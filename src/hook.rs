@@ -0,0 +1,177 @@
+use crate::ivec::IVec;
+use crate::transaction::WriteSet;
+use crate::tree::{IndexedTransactionTrees, TransactionTrees};
+use crate::Result;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// Sees the write set of a committing transaction, and the transaction itself to read the
+/// written values. Returning an error vetoes the commit.
+pub type PreCommitHook = Arc<dyn Fn(&TransactionView, &WriteSet) -> Result<()> + Send + Sync>;
+
+/// Called with the id of a transaction once its commit is done, on a thread of the database
+/// rather than the one committing.
+pub type PostCommitHook = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// A committing transaction as its pre-commit hooks see it: its trees, written values included,
+/// but read-only, as the write set is final by then.
+pub struct TransactionView<'t, 'a> {
+    trees: &'t TransactionTrees<'a>,
+}
+
+impl<'t, 'a> TransactionView<'t, 'a> {
+    pub fn get(&self, idx: usize) -> TreeView<'t, 'a> {
+        TreeView {
+            tree: self.trees.get(idx),
+        }
+    }
+
+    pub fn tree(&self, name: &str) -> Option<TreeView<'t, 'a>> {
+        self.trees.tree(name).map(|tree| TreeView { tree })
+    }
+}
+
+/// One tree of a [`TransactionView`].
+pub struct TreeView<'t, 'a> {
+    tree: IndexedTransactionTrees<'t, 'a>,
+}
+
+impl<'t, 'a> TreeView<'t, 'a> {
+    pub fn get<K>(&self, key: K) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+    {
+        self.tree.get(key)
+    }
+
+    pub fn get_many<I, K>(&self, keys: I) -> Result<Vec<Option<IVec>>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        self.tree.get_many(keys)
+    }
+
+    pub fn scan<K, R>(&self, keys: R) -> Result<Vec<IVec>>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.tree.scan(keys)
+    }
+
+    pub fn keys<K, R>(&self, keys: R) -> Result<Vec<String>>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.tree.keys(keys)
+    }
+}
+
+/// The commit hooks of a database. A hook registered for a tree only runs for transactions that
+/// write to that tree; one registered without a tree runs for every commit.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub pre_commit: Vec<(Option<String>, PreCommitHook)>,
+    pub post_commit: Vec<(Option<String>, PostCommitHook)>,
+}
+
+impl Hooks {
+    /// Runs the pre-commit hooks that apply to `write_set`, stopping at the first veto.
+    pub fn pre_commit(&self, trees: &TransactionTrees, write_set: &WriteSet) -> Result<()> {
+        let view = TransactionView { trees };
+        for (tree, hook) in self.pre_commit.iter() {
+            if Self::applies(tree, write_set) {
+                hook(&view, write_set)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The post-commit hooks that apply to `write_set`.
+    pub fn post_commit(&self, write_set: &WriteSet) -> Vec<PostCommitHook> {
+        self.post_commit
+            .iter()
+            .filter(|(tree, _)| Self::applies(tree, write_set))
+            .map(|(_, hook)| hook.clone())
+            .collect()
+    }
+
+    fn applies(tree: &Option<String>, write_set: &WriteSet) -> bool {
+        match tree {
+            Some(name) => write_set.trees.iter().any(|writes| writes.name == *name),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::Db;
+    use crate::error::Error;
+    use crate::transaction::Durability;
+    use crate::tree::TransactionOptions;
+    use spin::Mutex;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_hooks() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        db.add_pre_commit_hook(Some("balances"), |trees, write_set| {
+            let balances = trees.tree("balances").unwrap();
+            for writes in write_set.trees.iter().filter(|w| w.name == "balances") {
+                for (key, _) in writes.writes.iter() {
+                    if let Some(value) = balances.get(key.as_str())? {
+                        if value.starts_with(b"-") {
+                            return Err(Error::Rejected(format!("{} is negative", key)));
+                        }
+                    }
+                }
+            }
+            Ok(())
+        });
+        let committed = Arc::new(Mutex::new(vec![]));
+        let ids = committed.clone();
+        db.add_post_commit_hook(Some("balances"), move |id| ids.lock().push(id));
+
+        let trees = db.start_transaction(["balances"]).unwrap();
+        trees.get(0).set("alice", "10").unwrap();
+        let info = trees.commit().unwrap();
+        assert_eq!(*committed.lock(), vec![info.transaction_id]);
+
+        let trees = db.start_transaction(["balances"]).unwrap();
+        trees.get(0).set("alice", "-5").unwrap();
+        assert!(matches!(trees.commit(), Err(Error::Rejected(_))));
+        drop(trees);
+        // other trees are not watched
+        let trees = db.start_transaction(["other"]).unwrap();
+        trees.get(0).set("bob", "-5").unwrap();
+        trees.commit().unwrap();
+        assert_eq!(committed.lock().len(), 1);
+
+        let snapshot = db.read_transaction(["balances"]).unwrap();
+        assert_eq!(snapshot.get(0).get("alice").unwrap(), Some("10".into()));
+
+        // commits nobody waits for run their hooks once done
+        let options = TransactionOptions {
+            durability: Durability::None,
+            ..Default::default()
+        };
+        let trees = db.start_transaction_with(["balances"], options).unwrap();
+        trees.get(0).set("alice", "20").unwrap();
+        let lazy = trees.commit().unwrap().transaction_id;
+        let trees = db.start_transaction(["balances"]).unwrap();
+        trees.get(0).set("alice", "30").unwrap();
+        let dropped = trees.commit_async().unwrap().info.transaction_id;
+        while committed.lock().len() < 3 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        committed.lock().sort_unstable();
+        assert_eq!(committed.lock()[1..], [lazy, dropped]);
+    }
+}
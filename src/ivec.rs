@@ -0,0 +1,123 @@
+use std::borrow::Borrow;
+use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// An immutable, reference counted byte string. Cloning only bumps the reference count, so the
+/// same value can be handed out by the cache and by every reader without copying.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IVec(Arc<[u8]>);
+
+impl IVec {
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+impl Deref for IVec {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for IVec {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for IVec {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Debug for IVec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.0[..], f)
+    }
+}
+
+impl From<Vec<u8>> for IVec {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<&[u8]> for IVec {
+    fn from(value: &[u8]) -> Self {
+        Self(value.into())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for IVec {
+    fn from(value: &[u8; N]) -> Self {
+        Self(value[..].into())
+    }
+}
+
+impl From<&str> for IVec {
+    fn from(value: &str) -> Self {
+        Self(value.as_bytes().into())
+    }
+}
+
+impl From<String> for IVec {
+    fn from(value: String) -> Self {
+        Self(value.into_bytes().into())
+    }
+}
+
+impl From<Arc<[u8]>> for IVec {
+    fn from(value: Arc<[u8]>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<IVec> for Vec<u8> {
+    fn from(value: IVec) -> Self {
+        value.0.to_vec()
+    }
+}
+
+impl From<IVec> for Arc<[u8]> {
+    fn from(value: IVec) -> Self {
+        value.0
+    }
+}
+
+impl PartialEq<[u8]> for IVec {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.0[..] == *other
+    }
+}
+
+impl PartialEq<&[u8]> for IVec {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self.0[..] == **other
+    }
+}
+
+impl PartialEq<Vec<u8>> for IVec {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.0[..] == other[..]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ivec::IVec;
+
+    #[test]
+    fn test_ivec() {
+        let value = IVec::from(vec![1_u8, 2, 3]);
+        let cloned = value.clone();
+        assert_eq!(value.as_ptr(), cloned.as_ptr());
+        assert_eq!(value, vec![1_u8, 2, 3]);
+        assert_eq!(&value[..], &[1_u8, 2, 3]);
+        assert_eq!(Vec::from(cloned), vec![1_u8, 2, 3]);
+        assert_eq!(IVec::from("abc"), IVec::from(b"abc"));
+    }
+}
@@ -1,12 +1,18 @@
-#[warn(dead_code)]
+#![warn(dead_code)]
 extern crate core;
 
 use crate::error::Error;
 
+pub mod codec;
 pub mod db;
 pub mod error;
+pub mod hook;
+pub mod ivec;
 pub mod lock;
 pub mod lru_map;
+pub mod monitor;
+pub mod secondary;
+pub mod snapshot;
 pub mod state;
 pub mod thread_pool;
 pub mod transaction;
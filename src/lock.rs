@@ -1,41 +1,98 @@
 use crate::{Error, Result};
-use gstuff::oneshot::{oneshot, Sender};
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender};
 use spin::mutex::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct Lock {
     pub locked: AtomicBool,
+    /// The owner holding the lock, if it was taken on behalf of one.
+    pub holder: Mutex<Option<usize>>,
     pub pendings: Mutex<Vec<Sender<()>>>,
 }
 
+impl Default for Lock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Lock {
     pub fn new() -> Self {
         Self {
             locked: AtomicBool::new(false),
+            holder: Mutex::new(None),
             pendings: Mutex::new(vec![]),
         }
     }
+
+    pub fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire)
+            .is_ok()
+    }
+
     pub fn lock(&self) -> Result<()> {
+        self.lock_until(None)
+    }
+
+    /// Like [`Lock::lock`], failing with [`Error::LockTimeout`] once `timeout` has passed.
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<()> {
+        self.lock_until(Some(Instant::now() + timeout))
+    }
+
+    pub fn lock_until(&self, deadline: Option<Instant>) -> Result<()> {
         loop {
-            if self
-                .locked
-                .compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire)
-                .is_ok()
-            {
+            if self.try_lock() {
+                return Ok(());
+            }
+            let mut guard = self.pendings.lock();
+            if self.try_lock() {
                 return Ok(());
             }
+            let (tx, rx) = bounded(1);
+            guard.push(tx);
+            drop(guard);
+            wait(&rx, deadline)?;
+        }
+    }
+
+    /// Like [`Lock::try_lock`], taking the lock on behalf of `owner`.
+    pub fn try_lock_for(&self, owner: usize) -> bool {
+        let _guard = self.pendings.lock();
+        let locked = self.try_lock();
+        if locked {
+            *self.holder.lock() = Some(owner);
+        }
+        locked
+    }
+
+    /// Like [`Lock::lock_until`], taking the lock on behalf of `owner` and recording in
+    /// `waits` that the owner waits for the holder. Fails with [`Error::Deadlock`] when the
+    /// holder waits for `owner` itself, and with [`Error::Aborted`] once the owner is aborted.
+    pub fn lock_for(
+        &self,
+        owner: usize,
+        waits: &WaitGraph,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        loop {
             let mut guard = self.pendings.lock();
-            if !self
-                .locked
-                .compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire)
-                .is_ok()
-            {
+            waits.check(owner)?;
+            if self.try_lock() {
+                *self.holder.lock() = Some(owner);
+                waits.done(owner);
                 return Ok(());
             }
-            let (tx, rx) = oneshot();
+            let holders = self.holder.lock().iter().cloned().collect();
+            let (tx, rx) = bounded(1);
+            waits.wait(owner, holders, tx.clone())?;
             guard.push(tx);
             drop(guard);
-            return Ok(rx.recv().map_err(Error::Unknown)?);
+            wait(&rx, deadline).inspect_err(|_| waits.done(owner))?;
         }
     }
 
@@ -46,18 +103,245 @@ impl Lock {
             .compare_exchange(true, false, Ordering::SeqCst, Ordering::Acquire)
             .is_ok()
         {
+            *self.holder.lock() = None;
             for pending in guard.drain(..) {
-                pending.send(())
+                // waiters that timed out are gone
+                let _ = pending.send(());
             }
         }
     }
 }
 
+fn wait(rx: &Receiver<()>, deadline: Option<Instant>) -> Result<()> {
+    let received = match deadline {
+        Some(deadline) => rx.recv_deadline(deadline),
+        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    };
+    match received {
+        Ok(()) => Ok(()),
+        Err(RecvTimeoutError::Timeout) => Err(Error::LockTimeout),
+        Err(RecvTimeoutError::Disconnected) => Err(Error::Unknown("lock dropped".to_owned())),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+pub type KeyRange = (Bound<String>, Bound<String>);
+
+pub struct KeyLock {
+    pub owner: usize,
+    pub range: KeyRange,
+    pub mode: LockMode,
+}
+
+/// Shared and exclusive locks on the keys and key ranges of one tree. A single key is locked as
+/// the range holding just that key. Locks of different owners conflict when their ranges overlap
+/// and one of them is exclusive.
+pub struct KeyLocks {
+    pub held: Mutex<Vec<KeyLock>>,
+    pub pendings: Mutex<Vec<Sender<()>>>,
+    /// Shared by the key locks of every tree of the database.
+    pub waits: Arc<WaitGraph>,
+}
+
+impl KeyLocks {
+    pub fn new(waits: Arc<WaitGraph>) -> Self {
+        Self {
+            held: Mutex::new(vec![]),
+            pendings: Mutex::new(vec![]),
+            waits,
+        }
+    }
+
+    pub fn key(key: &str) -> KeyRange {
+        (
+            Bound::Included(key.to_owned()),
+            Bound::Included(key.to_owned()),
+        )
+    }
+
+    /// Waits until `range` can be locked in `mode` by `owner` and locks it. Returns `false` when
+    /// the owner held the lock already. Fails with [`Error::Deadlock`] when the owners holding
+    /// the range wait for `owner` themselves, with [`Error::LockTimeout`] once `deadline` has
+    /// passed, and with [`Error::Aborted`] once the owner is aborted.
+    pub fn lock(
+        &self,
+        owner: usize,
+        range: KeyRange,
+        mode: LockMode,
+        deadline: Option<Instant>,
+    ) -> Result<bool> {
+        loop {
+            let mut held = self.held.lock();
+            self.waits.check(owner)?;
+            if held.iter().any(|lock| {
+                lock.owner == owner
+                    && lock.mode >= mode
+                    && (lock.range == range || lock.range == (Bound::Unbounded, Bound::Unbounded))
+            }) {
+                return Ok(false);
+            }
+            let holders = Self::holders(&held, owner, &range, mode);
+            if holders.is_empty() {
+                self.waits.done(owner);
+                held.push(KeyLock { owner, range, mode });
+                return Ok(true);
+            }
+            let (tx, rx) = bounded(1);
+            self.waits.wait(owner, holders, tx.clone())?;
+            self.pendings.lock().push(tx);
+            drop(held);
+            wait(&rx, deadline).inspect_err(|_| self.waits.done(owner))?;
+        }
+    }
+
+    /// Locks `range` if that needs no waiting.
+    pub fn try_lock(&self, owner: usize, range: KeyRange, mode: LockMode) -> bool {
+        let mut held = self.held.lock();
+        if !Self::holders(&held, owner, &range, mode).is_empty() {
+            return false;
+        }
+        held.push(KeyLock { owner, range, mode });
+        true
+    }
+
+    /// Whether `owner` could lock `range` in `mode` without waiting.
+    pub fn is_free(&self, owner: usize, range: &KeyRange, mode: LockMode) -> bool {
+        Self::holders(&self.held.lock(), owner, range, mode).is_empty()
+    }
+
+    /// Releases every lock of `owner`.
+    pub fn release(&self, owner: usize) {
+        let mut held = self.held.lock();
+        let len = held.len();
+        held.retain(|lock| lock.owner != owner);
+        if held.len() != len {
+            for pending in self.pendings.lock().drain(..) {
+                let _ = pending.send(());
+            }
+        }
+    }
+
+    /// The other owners whose locks keep `owner` from locking `range` in `mode`.
+    fn holders(held: &[KeyLock], owner: usize, range: &KeyRange, mode: LockMode) -> Vec<usize> {
+        let mut holders: Vec<usize> = held
+            .iter()
+            .filter(|lock| {
+                lock.owner != owner
+                    && (lock.mode == LockMode::Exclusive || mode == LockMode::Exclusive)
+                    && overlaps(&lock.range, range)
+            })
+            .map(|lock| lock.owner)
+            .collect();
+        holders.sort_unstable();
+        holders.dedup();
+        holders
+    }
+}
+
+/// Which owners each waiting owner of key locks and tree locks waits for.
+#[derive(Default)]
+pub struct WaitGraph {
+    pub edges: Mutex<HashMap<usize, Vec<usize>>>,
+    /// Wakes each waiting owner, see [`WaitGraph::abort`].
+    pub wakers: Mutex<HashMap<usize, Sender<()>>>,
+    /// The aborted owners, which cannot take any lock until they are forgotten.
+    pub aborted: Mutex<HashSet<usize>>,
+}
+
+impl WaitGraph {
+    /// Records that `owner` waits for `holders` until `waker` is signalled, or fails with
+    /// [`Error::Deadlock`] if one of them waits for `owner`, directly or not, and with
+    /// [`Error::Aborted`] if the owner is aborted.
+    pub fn wait(&self, owner: usize, holders: Vec<usize>, waker: Sender<()>) -> Result<()> {
+        let mut edges = self.edges.lock();
+        if self.aborted.lock().contains(&owner) {
+            return Err(Error::Aborted);
+        }
+        let mut stack = holders.clone();
+        let mut seen = HashSet::new();
+        while let Some(next) = stack.pop() {
+            if next == owner {
+                edges.remove(&owner);
+                return Err(Error::Deadlock);
+            }
+            if seen.insert(next) {
+                if let Some(waits) = edges.get(&next) {
+                    stack.extend(waits.iter().cloned());
+                }
+            }
+        }
+        edges.insert(owner, holders);
+        self.wakers.lock().insert(owner, waker);
+        Ok(())
+    }
+
+    pub fn done(&self, owner: usize) {
+        self.edges.lock().remove(&owner);
+        self.wakers.lock().remove(&owner);
+    }
+
+    /// Fails with [`Error::Aborted`] if `owner` is aborted.
+    pub fn check(&self, owner: usize) -> Result<()> {
+        match self.aborted.lock().contains(&owner) {
+            true => Err(Error::Aborted),
+            false => Ok(()),
+        }
+    }
+
+    /// Keeps `owner` from taking any more locks and wakes it up if it waits for one, so that
+    /// the wait fails with [`Error::Aborted`].
+    pub fn abort(&self, owner: usize) {
+        let mut edges = self.edges.lock();
+        self.aborted.lock().insert(owner);
+        edges.remove(&owner);
+        if let Some(waker) = self.wakers.lock().remove(&owner) {
+            // the channel may hold a wake-up from an unlock already
+            let _ = waker.try_send(());
+        }
+    }
+
+    /// Drops what is known about `owner` once it is gone.
+    pub fn forget(&self, owner: usize) {
+        self.done(owner);
+        self.aborted.lock().remove(&owner);
+    }
+
+    /// The owners `owner` waits for.
+    pub fn waits_for(&self, owner: usize) -> Vec<usize> {
+        self.edges.lock().get(&owner).cloned().unwrap_or_default()
+    }
+}
+
+fn overlaps(a: &KeyRange, b: &KeyRange) -> bool {
+    !ends_before(&a.1, &b.0) && !ends_before(&b.1, &a.0)
+}
+
+/// Whether every key up to `hi` sorts before every key from `lo` on.
+fn ends_before(hi: &Bound<String>, lo: &Bound<String>) -> bool {
+    match (hi, lo) {
+        (Bound::Unbounded, _) | (_, Bound::Unbounded) => false,
+        (Bound::Included(hi), Bound::Included(lo)) => hi < lo,
+        (Bound::Included(hi), Bound::Excluded(lo))
+        | (Bound::Excluded(hi), Bound::Included(lo))
+        | (Bound::Excluded(hi), Bound::Excluded(lo)) => hi <= lo,
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::lock::Lock;
+    use crate::error::Error;
+    use crate::lock::{KeyLocks, Lock, LockMode, WaitGraph};
+    use crossbeam::channel::bounded;
     use crossbeam::sync::WaitGroup;
+    use std::ops::Bound;
+    use std::time::{Duration, Instant};
 
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
     use std::thread;
 
@@ -105,4 +389,108 @@ mod test {
         wg.wait();
         assert_eq!(num, 100);
     }
+
+    #[test]
+    fn test_key_locks() {
+        let locks = KeyLocks::new(Default::default());
+        let range = |lo: &str, hi: &str| {
+            (
+                Bound::Included(lo.to_owned()),
+                Bound::Excluded(hi.to_owned()),
+            )
+        };
+        assert!(locks
+            .lock(1, KeyLocks::key("b"), LockMode::Shared, None)
+            .unwrap());
+        assert!(!locks
+            .lock(1, KeyLocks::key("b"), LockMode::Shared, None)
+            .unwrap());
+        assert!(locks.is_free(2, &KeyLocks::key("b"), LockMode::Shared));
+        assert!(!locks.is_free(2, &KeyLocks::key("b"), LockMode::Exclusive));
+        assert!(locks.is_free(2, &KeyLocks::key("c"), LockMode::Exclusive));
+        // the owner upgrades its own lock
+        assert!(locks
+            .lock(1, KeyLocks::key("b"), LockMode::Exclusive, None)
+            .unwrap());
+        assert!(!locks.is_free(2, &range("a", "c"), LockMode::Shared));
+        assert!(locks.is_free(2, &range("c", "d"), LockMode::Exclusive));
+        assert!(locks.is_free(2, &range("a", "b"), LockMode::Exclusive));
+        // each holder is listed once, however many of its locks are in the way
+        assert!(locks.try_lock(3, KeyLocks::key("a"), LockMode::Shared));
+        assert!(locks.try_lock(1, KeyLocks::key("a"), LockMode::Shared));
+        let held = locks.held.lock();
+        let holders = KeyLocks::holders(&held, 2, &range("a", "c"), LockMode::Exclusive);
+        assert_eq!(holders, vec![1, 3]);
+        drop(held);
+        locks.release(3);
+        locks.release(1);
+        assert!(locks.is_free(
+            2,
+            &(Bound::Unbounded, Bound::Unbounded),
+            LockMode::Exclusive
+        ));
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let lock = Lock::new();
+        assert!(lock.try_lock());
+        assert!(!lock.try_lock());
+        let timeout = Duration::from_millis(10);
+        assert!(matches!(
+            lock.lock_timeout(timeout),
+            Err(Error::LockTimeout)
+        ));
+        lock.unlock();
+        lock.lock_timeout(timeout).unwrap();
+
+        let waker = || bounded(1).0;
+        let waits = WaitGraph::default();
+        waits.wait(1, vec![2], waker()).unwrap();
+        waits.wait(2, vec![3], waker()).unwrap();
+        assert!(matches!(
+            waits.wait(3, vec![1], waker()),
+            Err(Error::Deadlock)
+        ));
+        waits.done(1);
+        waits.wait(3, vec![1], waker()).unwrap();
+        assert_eq!(waits.waits_for(3), vec![1]);
+
+        // waiting for a tree lock counts as waiting for its holder
+        let waits = WaitGraph::default();
+        let lock = Lock::new();
+        assert!(lock.try_lock_for(1));
+        waits.wait(1, vec![2], waker()).unwrap();
+        assert!(matches!(
+            lock.lock_for(2, &waits, None),
+            Err(Error::Deadlock)
+        ));
+        assert!(matches!(
+            lock.lock_for(3, &waits, Some(Instant::now() + timeout)),
+            Err(Error::LockTimeout)
+        ));
+        assert!(waits.waits_for(3).is_empty());
+        lock.unlock();
+        assert_eq!(*lock.holder.lock(), None);
+        lock.lock_for(2, &waits, None).unwrap();
+        assert_eq!(*lock.holder.lock(), Some(2));
+
+        // aborting a waiting owner wakes it up without handing it the lock
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| lock.lock_for(3, &waits, None));
+            while waits.waits_for(3).is_empty() {
+                thread::yield_now();
+            }
+            waits.abort(3);
+            assert!(matches!(waiter.join().unwrap(), Err(Error::Aborted)));
+        });
+        lock.unlock();
+        assert!(matches!(
+            lock.lock_for(3, &waits, None),
+            Err(Error::Aborted)
+        ));
+        assert!(!lock.locked.load(Ordering::SeqCst));
+        waits.forget(3);
+        lock.lock_for(3, &waits, None).unwrap();
+    }
 }
@@ -2,22 +2,31 @@ use arrayvec::ArrayVec;
 use std::collections::HashMap;
 use std::hash::Hash;
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 pub struct LruMap<K, V, const N: usize> {
     pub cache: ArrayVec<Entry<K, V>, N>,
     pub head: usize,
     pub tail: usize,
-    pub indexes: HashMap<Rc<K>, usize>,
+    pub indexes: HashMap<Arc<K>, usize>,
 }
 
 pub struct Entry<K, V> {
-    pub key: Rc<K>,
+    pub key: Arc<K>,
     pub value: V,
     pub pre: usize,
     pub next: usize,
 }
 
+impl<K, V, const N: usize> Default for LruMap<K, V, N>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<K, V, const N: usize> LruMap<K, V, N>
 where
     K: Eq + Hash,
@@ -32,7 +41,14 @@ where
     }
 
     pub fn insert(&mut self, key: K, value: V) {
-        let key = Rc::new(key);
+        if let Some(idx) = self.indexes.get(&key) {
+            let idx = *idx;
+            self.cache[idx].value = value;
+            self.remove(idx);
+            self.push_front(idx);
+            return;
+        }
+        let key = Arc::new(key);
         let entry = Entry {
             key: key.clone(),
             value,
@@ -48,6 +64,7 @@ where
             let old = self.pop_back();
             self.indexes.remove(&self.cache[old].key);
             let _ = std::mem::replace(&mut self.cache[old], entry);
+            self.push_front(old);
             self.indexes.insert(key, old);
         }
     }
@@ -106,5 +123,11 @@ mod test {
         assert_eq!(lru.get(&"key2"), Some(&2));
         assert_eq!(lru.get(&"key3"), Some(&3));
         assert_eq!(lru.get(&"key4"), Some(&4));
+        lru.insert("key2", 22);
+        lru.insert("key5", 5);
+        assert!(lru.get(&"key3").is_none());
+        assert_eq!(lru.get(&"key2"), Some(&22));
+        assert_eq!(lru.get(&"key4"), Some(&4));
+        assert_eq!(lru.get(&"key5"), Some(&5));
     }
 }
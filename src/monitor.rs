@@ -0,0 +1,431 @@
+use crate::lock::{KeyRange, Lock, LockMode, WaitGraph};
+use crate::state::PublicState;
+use crate::transaction::TransactionAction;
+use crate::tree::{LockGranularity, PreparedTransaction, TransactionMode};
+use crate::{Error, Result};
+use crossbeam::channel::{bounded, RecvTimeoutError, Sender};
+use spin::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const RUNNING: u8 = 0;
+const COMMITTING: u8 = 1;
+const ABORTED: u8 = 2;
+
+/// The part of a running transaction the database can see, and abort.
+pub struct ActiveTransaction {
+    pub owner: usize,
+    pub started: Instant,
+    pub mode: TransactionMode,
+    pub granularity: LockGranularity,
+    pub trees: Mutex<Vec<(String, PublicState)>>,
+    /// Tree locks held by the transaction.
+    pub locks: Mutex<Vec<Arc<Lock>>>,
+    /// Taken once the tree locks are held: when the transaction starts, or when a transaction
+    /// that does not lock whole trees commits. Commits are acknowledged in id order, so an id
+    /// must not be held by a transaction that may still run for a while.
+    pub transaction_id: Mutex<Option<usize>>,
+    /// The tree whose lock the transaction waits for.
+    pub waiting: Mutex<Option<String>>,
+    state: AtomicU8,
+}
+
+impl ActiveTransaction {
+    pub fn new(owner: usize, mode: TransactionMode, granularity: LockGranularity) -> Self {
+        Self {
+            owner,
+            started: Instant::now(),
+            mode,
+            granularity,
+            trees: Mutex::new(vec![]),
+            locks: Mutex::new(vec![]),
+            transaction_id: Mutex::new(None),
+            waiting: Mutex::new(None),
+            state: AtomicU8::new(RUNNING),
+        }
+    }
+
+    /// Keeps the transaction from being aborted while it commits, or fails with
+    /// [`Error::Aborted`] if it was aborted already.
+    pub fn start_commit(&self) -> Result<()> {
+        match self
+            .state
+            .compare_exchange(RUNNING, COMMITTING, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Err(ABORTED) => Err(Error::Aborted),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == ABORTED
+    }
+
+    /// Releases the locks of the transaction and hands its id to `drop_id`, unless it is
+    /// committing. A lock wait of the transaction is woken up through `waits`. Every later
+    /// lock, write or commit of the transaction fails with [`Error::Aborted`].
+    pub fn abort<F: FnOnce(usize)>(&self, waits: &WaitGraph, drop_id: F) -> bool {
+        if self
+            .state
+            .compare_exchange(RUNNING, ABORTED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return false;
+        }
+        waits.abort(self.owner);
+        for (_, public) in self.trees.lock().iter() {
+            public.keys.release(self.owner);
+        }
+        for lock in self.locks.lock().drain(..) {
+            lock.unlock();
+        }
+        if let Some(id) = self.transaction_id.lock().take() {
+            drop_id(id);
+        }
+        true
+    }
+}
+
+/// What [`Db::active_transactions`](crate::db::Db::active_transactions) tells about a
+/// transaction.
+#[derive(Clone, Debug)]
+pub struct TransactionStatus {
+    /// Identifies the transaction to the key locks.
+    pub owner: usize,
+    pub transaction_id: Option<usize>,
+    pub age: Duration,
+    pub mode: TransactionMode,
+    pub granularity: LockGranularity,
+    pub trees: Vec<String>,
+    /// The trees whose tree lock the transaction holds.
+    pub locked_trees: Vec<String>,
+    /// The key locks the transaction holds, by tree.
+    pub key_locks: Vec<(String, KeyRange, LockMode)>,
+    /// The tree whose lock the transaction waits for.
+    pub waiting_for: Option<String>,
+    /// The transactions, by owner, holding the key or tree locks the transaction waits for.
+    pub blocked_by: Vec<usize>,
+    pub aborted: bool,
+    /// Whether the transaction is prepared, see [`Db::in_doubt`](crate::db::Db::in_doubt).
+    pub prepared: bool,
+}
+
+pub type ActiveTransactions = Arc<Mutex<HashMap<usize, Arc<ActiveTransaction>>>>;
+
+/// Describes the transactions of `active`, oldest first.
+pub fn statuses(active: &ActiveTransactions, waits: &WaitGraph) -> Vec<TransactionStatus> {
+    let active: Vec<Arc<ActiveTransaction>> = active.lock().values().cloned().collect();
+    let locked_trees = |transaction: &ActiveTransaction| -> Vec<String> {
+        let locks = transaction.locks.lock();
+        transaction
+            .trees
+            .lock()
+            .iter()
+            .filter(|(_, public)| locks.iter().any(|lock| Arc::ptr_eq(lock, &public.lock)))
+            .map(|(name, _)| name.clone())
+            .collect()
+    };
+    let mut statuses: Vec<TransactionStatus> = active
+        .iter()
+        .map(|transaction| {
+            let locked_trees = locked_trees(transaction);
+            let trees = transaction.trees.lock();
+            let key_locks = key_locks(
+                transaction.owner,
+                trees.iter().map(|(name, public)| (name.as_str(), public)),
+            );
+            let waiting_for = transaction.waiting.lock().clone();
+            TransactionStatus {
+                owner: transaction.owner,
+                transaction_id: *transaction.transaction_id.lock(),
+                age: transaction.started.elapsed(),
+                mode: transaction.mode,
+                granularity: transaction.granularity,
+                trees: trees.iter().map(|(name, _)| name.clone()).collect(),
+                locked_trees,
+                key_locks,
+                waiting_for,
+                blocked_by: waits.waits_for(transaction.owner),
+                aborted: transaction.is_aborted(),
+                prepared: false,
+            }
+        })
+        .collect();
+    statuses.sort_by_key(|status| std::cmp::Reverse(status.age));
+    statuses
+}
+
+/// Describes the transactions of `prepared`, by id, which hold their locks until they are
+/// committed or rolled back.
+pub fn prepared_statuses(prepared: &HashMap<usize, PreparedTransaction>) -> Vec<TransactionStatus> {
+    prepared
+        .iter()
+        .map(|(transaction_id, transaction)| {
+            let trees = || {
+                transaction
+                    .trees
+                    .iter()
+                    .map(|tree| (tree.name.as_str(), &tree.state.public))
+            };
+            TransactionStatus {
+                owner: transaction.owner,
+                transaction_id: Some(*transaction_id),
+                age: transaction.started.elapsed(),
+                mode: transaction.options.mode,
+                granularity: transaction.options.granularity,
+                trees: trees().map(|(name, _)| name.to_owned()).collect(),
+                locked_trees: trees()
+                    .filter(|(_, public)| {
+                        let held = |lock: &Arc<Lock>| Arc::ptr_eq(lock, &public.lock);
+                        transaction.locks.iter().any(held)
+                    })
+                    .map(|(name, _)| name.to_owned())
+                    .collect(),
+                key_locks: key_locks(transaction.owner, trees()),
+                waiting_for: None,
+                blocked_by: vec![],
+                aborted: false,
+                prepared: true,
+            }
+        })
+        .collect()
+}
+
+/// The key locks `owner` holds on `trees`.
+fn key_locks<'t>(
+    owner: usize,
+    trees: impl Iterator<Item = (&'t str, &'t PublicState)>,
+) -> Vec<(String, KeyRange, LockMode)> {
+    trees
+        .flat_map(|(name, public)| {
+            public
+                .keys
+                .held
+                .lock()
+                .iter()
+                .filter(|lock| lock.owner == owner)
+                .map(|lock| (name.to_owned(), lock.range.clone(), lock.mode))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+pub type WatchdogReport = Arc<dyn Fn(&TransactionStatus) + Send + Sync>;
+
+/// Looks for transactions open for longer than `threshold` every `interval`, reports them and
+/// aborts them if `abort` is set.
+#[derive(Clone)]
+pub struct Watchdog {
+    pub threshold: Duration,
+    pub interval: Duration,
+    pub abort: bool,
+    pub report: Option<WatchdogReport>,
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("threshold", &self.threshold)
+            .field("interval", &self.interval)
+            .field("abort", &self.abort)
+            .finish()
+    }
+}
+
+/// The thread of a running [`Watchdog`], stopped when dropped.
+pub struct WatchdogHandle {
+    pub stop: Option<Sender<()>>,
+    pub handle: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Starts watching `active`. Aborted transactions hand their ids to `log` as dropped.
+    pub fn start(
+        self,
+        active: ActiveTransactions,
+        waits: Arc<WaitGraph>,
+        log: Sender<TransactionAction>,
+    ) -> WatchdogHandle {
+        let (stop, stopped) = bounded::<()>(0);
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(self.interval) {
+                for status in statuses(&active, &waits) {
+                    if status.age < self.threshold || status.aborted {
+                        continue;
+                    }
+                    if let Some(report) = &self.report {
+                        report(&status);
+                    }
+                    if !self.abort {
+                        continue;
+                    }
+                    let transaction = active.lock().get(&status.owner).cloned();
+                    if let Some(transaction) = transaction {
+                        transaction.abort(&waits, |id| {
+                            let _ = log.send(TransactionAction::Drop(id));
+                        });
+                    }
+                }
+            }
+        });
+        WatchdogHandle {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for WatchdogHandle {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::{Config, Db};
+    use crate::error::Error;
+    use crate::monitor::Watchdog;
+    use crossbeam::channel::bounded;
+    use spin::Mutex;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_active_transactions() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let holder = db.start_transaction(["a", "b"]).unwrap();
+        holder.get(0).set("k", "holder").unwrap();
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let trees = db.start_transaction(["a"]).unwrap();
+                trees.get(0).set("k", "waiter").unwrap();
+                trees.commit().unwrap();
+            });
+            let statuses = loop {
+                let statuses = db.active_transactions();
+                if statuses.len() == 2 && !statuses[1].blocked_by.is_empty() {
+                    break statuses;
+                }
+                thread::yield_now();
+            };
+            assert_eq!(statuses[0].owner, holder.owner);
+            assert_eq!(statuses[0].transaction_id, holder.transaction_id());
+            assert_eq!(statuses[0].locked_trees, vec!["a", "b"]);
+            assert_eq!(statuses[1].blocked_by, vec![holder.owner]);
+            assert!(db.abort_transaction(holder.owner));
+            waiter.join().unwrap();
+        });
+        assert!(matches!(holder.get(1).set("k", ""), Err(Error::Aborted)));
+        assert!(matches!(holder.commit(), Err(Error::Aborted)));
+        drop(holder);
+        assert!(db.active_transactions().is_empty());
+        let snapshot = db.read_transaction(["a"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("waiter".into()));
+    }
+
+    #[test]
+    fn test_abort_waiting() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let prepared = db.start_transaction(["a"]).unwrap();
+        prepared.get(0).set("k", "prepared").unwrap();
+        let prepared_owner = prepared.owner;
+        let id = prepared.prepare().unwrap();
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| db.start_transaction(["a"]).map(|_| ()));
+            let statuses = loop {
+                let statuses = db.active_transactions();
+                if statuses.len() == 2 && !statuses[1].blocked_by.is_empty() {
+                    break statuses;
+                }
+                thread::yield_now();
+            };
+            // the prepared transaction holds the lock the other one waits for
+            assert_eq!(statuses[0].owner, prepared_owner);
+            assert_eq!(statuses[0].transaction_id, Some(id));
+            assert!(statuses[0].prepared);
+            assert_eq!(statuses[0].locked_trees, vec!["a"]);
+            assert_eq!(statuses[1].blocked_by, vec![prepared_owner]);
+            assert!(db.abort_transaction(statuses[1].owner));
+            assert!(matches!(waiter.join().unwrap(), Err(Error::Aborted)));
+        });
+        assert_eq!(db.active_transactions().len(), 1);
+        db.commit_prepared(id).unwrap();
+        let snapshot = db.read_transaction(["a"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("prepared".into()));
+        // the aborted waiter did not take the lock on its way out
+        let trees = db.start_transaction(["a"]).unwrap();
+        assert_eq!(trees.get(0).get("k").unwrap(), Some("prepared".into()));
+
+        // a commit failing before it reaches the log leaves the transaction abortable
+        db.add_pre_commit_hook(None, |_, _| Err(Error::Unknown("refused".to_owned())));
+        assert!(trees.commit().is_err());
+        assert!(db.abort_transaction(trees.owner));
+    }
+
+    #[test]
+    fn test_abort_in_pre_commit() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let (entered_tx, entered) = bounded(0);
+        let (resume, resumed) = bounded::<()>(0);
+        db.add_pre_commit_hook(None, move |_, _| {
+            entered_tx.send(()).unwrap();
+            let _ = resumed.recv();
+            Ok(())
+        });
+        let trees = db.start_transaction(["a"]).unwrap();
+        trees.get(0).set("k", "v").unwrap();
+        thread::scope(|scope| {
+            let committer = scope.spawn(|| trees.commit());
+            entered.recv().unwrap();
+            assert!(db.abort_transaction(trees.owner));
+            resume.send(()).unwrap();
+            assert!(matches!(committer.join().unwrap(), Err(Error::Aborted)));
+        });
+        // nothing of the aborted commit reached the shared state of the tree
+        let snapshot = db.read_transaction(["a"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), None);
+        assert!(snapshot.get(0).public.index_log.lock().changes.is_empty());
+        assert!(snapshot.get(0).public.removals.lock().is_empty());
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let dir = tempdir().unwrap();
+        let reported = Arc::new(Mutex::new(vec![]));
+        let report = reported.clone();
+        let config = Config {
+            watchdog: Some(Watchdog {
+                threshold: Duration::from_millis(50),
+                interval: Duration::from_millis(5),
+                abort: true,
+                report: Some(Arc::new(move |status| report.lock().push(status.owner))),
+            }),
+            ..Default::default()
+        };
+        let db = Db::open_with(dir.path(), config).unwrap();
+        let stale = db.start_transaction(["a"]).unwrap();
+        while reported.lock().is_empty() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(reported.lock()[0], stale.owner);
+        assert!(matches!(stale.get(0).set("k", ""), Err(Error::Aborted)));
+        // the id of the aborted transaction does not hold back later commits
+        let trees = db.start_transaction(["a"]).unwrap();
+        trees.get(0).set("k", "v").unwrap();
+        trees.commit().unwrap();
+    }
+}
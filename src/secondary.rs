@@ -0,0 +1,94 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+pub type KeyExtractor = Arc<dyn Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// An index over the values of a primary tree. Every entry of the primary tree for which the
+/// extractor returns an index key gets one entry in the index tree, keyed by the index key
+/// followed by the primary key. The key says it all, so entries have empty values.
+pub struct SecondaryIndex {
+    pub name: String,
+    pub tree: String,
+    pub extractor: KeyExtractor,
+}
+
+const ESCAPE: u8 = 0;
+const ESCAPED_ZERO: u8 = 2;
+const SEPARATOR: u8 = 1;
+
+impl SecondaryIndex {
+    pub fn tree_name(&self) -> String {
+        Self::index_tree_name(self.name.as_str())
+    }
+
+    #[inline]
+    pub fn index_tree_name(name: &str) -> String {
+        format!("{}.index", name)
+    }
+
+    pub fn extract(&self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        (self.extractor)(key, value)
+    }
+
+    /// Builds the index tree key for `primary_key` under `index_key`. Zero bytes in the index key
+    /// are escaped so that entries for one index key always form a contiguous range.
+    pub fn entry_key(index_key: &[u8], primary_key: &[u8]) -> Vec<u8> {
+        let mut entry = Self::prefix(index_key);
+        entry.extend_from_slice(primary_key);
+        entry
+    }
+
+    /// The range of index tree keys holding the entries for `index_key`.
+    pub fn entry_range(index_key: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        let lo = Self::prefix(index_key);
+        let mut hi = lo.clone();
+        *hi.last_mut().unwrap() = SEPARATOR + 1;
+        (Bound::Included(lo), Bound::Excluded(hi))
+    }
+
+    /// Recovers the primary key from an index tree key.
+    pub fn primary_key(entry_key: &[u8]) -> &[u8] {
+        let mut i = 0;
+        while i + 1 < entry_key.len() {
+            if entry_key[i] == ESCAPE {
+                if entry_key[i + 1] == SEPARATOR {
+                    return &entry_key[i + 2..];
+                }
+                i += 2;
+            } else {
+                i += 1;
+            }
+        }
+        &entry_key[entry_key.len()..]
+    }
+
+    fn prefix(index_key: &[u8]) -> Vec<u8> {
+        let mut prefix = Vec::with_capacity(index_key.len() + 2);
+        for byte in index_key {
+            if *byte == ESCAPE {
+                prefix.extend_from_slice(&[ESCAPE, ESCAPED_ZERO]);
+            } else {
+                prefix.push(*byte);
+            }
+        }
+        prefix.extend_from_slice(&[ESCAPE, SEPARATOR]);
+        prefix
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::secondary::SecondaryIndex;
+    use std::ops::RangeBounds;
+
+    #[test]
+    fn test_entry_key() {
+        let entry = SecondaryIndex::entry_key(b"a\0b", b"key\0");
+        assert_eq!(SecondaryIndex::primary_key(&entry[..]), b"key\0");
+        let range = SecondaryIndex::entry_range(b"a\0b");
+        assert!(range.contains(&entry));
+        assert!(!range.contains(&SecondaryIndex::entry_key(b"a", b"key")));
+        assert!(!range.contains(&SecondaryIndex::entry_key(b"a\0bc", b"key")));
+        assert!(!range.contains(&SecondaryIndex::entry_key(b"a\0b\0", b"key")));
+    }
+}
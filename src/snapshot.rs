@@ -0,0 +1,533 @@
+use crate::db::Db;
+use crate::ivec::IVec;
+use crate::state::{Index, PublicState, VersionedState};
+use crate::transaction::{LogRecords, Phase, TransactionBatchBuilder, WriteSet};
+use crate::tree::str_bounds;
+use crate::{Error, Result};
+
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Range;
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A read-only view of several trees, frozen at the moment the transaction started.
+pub struct ReadTransaction<'a> {
+    pub trees: Vec<SnapshotTree>,
+    pub db: &'a Db,
+}
+
+pub struct SnapshotTree {
+    pub name: String,
+    pub state: Arc<VersionedState>,
+    pub public: PublicState,
+}
+
+impl<'a> ReadTransaction<'a> {
+    pub fn get(&self, idx: usize) -> &SnapshotTree {
+        assert!(idx < self.trees.len());
+        &self.trees[idx]
+    }
+
+    pub fn tree(&self, name: &str) -> Option<&SnapshotTree> {
+        self.trees.iter().find(|tree| tree.name == name)
+    }
+}
+
+/// The trees as they were right after the commit of the transaction with id `transaction_id`,
+/// in the order commits were logged, rebuilt from the index records of the tree files and the
+/// writes of the transaction log.
+pub struct HistoricalSnapshot<'a> {
+    pub transaction_id: usize,
+    /// The version of every tree committed to up to the transaction.
+    pub versions: HashMap<String, u64>,
+    pub db: &'a Db,
+}
+
+impl<'a> HistoricalSnapshot<'a> {
+    pub fn tree(&self, name: &str) -> Result<SnapshotTree> {
+        let public = self.db.public_state(name)?;
+        let version = self.versions.get(name).copied().unwrap_or(0);
+        Ok(SnapshotTree {
+            name: name.to_owned(),
+            state: self
+                .db
+                .state_at(name, &public, version)?
+                .ok_or(Error::HistoryUnavailable(self.transaction_id))?,
+            public,
+        })
+    }
+}
+
+/// A commit found in the log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LoggedCommit {
+    pub transaction_id: usize,
+    /// Where the record holding the writes starts, which is the record of the first phase for
+    /// a two-phase commit. `None` for commits read from the history file.
+    #[serde(skip)]
+    pub position: Option<u64>,
+    /// The versions the commit took its trees to.
+    pub versions: Vec<(String, u64)>,
+}
+
+/// What the history file, [`HISTORY_FILE`](crate::db::HISTORY_FILE), holds: the commits as of the last compaction of the log, which drops
+/// the records of those whose writes the tree files hold.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Archive {
+    /// The versions of the trees before the first of the commits.
+    pub base: HashMap<String, u64>,
+    pub commits: Vec<LoggedCommit>,
+}
+
+/// The commits of the history file and then of the transaction log, in the order they were
+/// logged, read as far as the log has been appended to when last asked, so that each record is
+/// only decoded once.
+#[derive(Default)]
+pub struct History {
+    /// How far the log has been read.
+    pub scanned: u64,
+    /// The versions of the trees before the first of [`History::commits`].
+    pub base: HashMap<String, u64>,
+    /// The versions of the trees after the last commit of the history file.
+    pub archived: HashMap<String, u64>,
+    pub commits: Vec<LoggedCommit>,
+    /// Where the writes that took each tree to each of its versions are logged.
+    pub positions: HashMap<String, BTreeMap<u64, u64>>,
+    /// The prepared transactions read but not finished yet, by id.
+    pub prepared: HashMap<usize, LoggedCommit>,
+    /// The records of the second phase of two-phase commits, along with the record of their
+    /// first phase.
+    pub decided: Vec<(u64, u64)>,
+    /// The records of prepared transactions that were rolled back, which nothing needs.
+    pub discarded: Vec<u64>,
+}
+
+impl History {
+    /// Starts from the commits of the history file at `path`, if there is one.
+    pub fn open(path: &Path) -> Result<Self> {
+        let archive: Archive = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes[..])?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Archive::default(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut archived = archive.base.clone();
+        for commit in archive.commits.iter() {
+            archived.extend(commit.versions.iter().cloned());
+        }
+        Ok(Self {
+            base: archive.base,
+            archived,
+            commits: archive.commits,
+            ..Default::default()
+        })
+    }
+
+    /// Reads the records appended to the log since the last call.
+    pub fn catch_up(&mut self, builder: &mut TransactionBatchBuilder) -> Result<()> {
+        let LogRecords { records, end, .. } = builder.records_from(self.scanned)?;
+        for (position, record) in records {
+            let Some(data) = record.data else {
+                continue;
+            };
+            let write_set: WriteSet = serde_json::from_slice(&data[..])?;
+            let commit = LoggedCommit {
+                transaction_id: record.transaction_id,
+                position: Some(position),
+                versions: write_set
+                    .trees
+                    .iter()
+                    .map(|writes| (writes.name.clone(), writes.version))
+                    .collect(),
+            };
+            match write_set.phase {
+                None => self.push(commit),
+                Some(Phase::Prepared) => {
+                    self.prepared.insert(commit.transaction_id, commit);
+                }
+                Some(Phase::Commit(prepared)) => {
+                    if let Some(prepared) = self.prepared.remove(&prepared) {
+                        self.decided
+                            .extend(prepared.position.map(|first| (first, position)));
+                        self.push(LoggedCommit {
+                            transaction_id: commit.transaction_id,
+                            ..prepared
+                        });
+                    }
+                }
+                Some(Phase::Rollback(prepared)) => {
+                    if let Some(prepared) = self.prepared.remove(&prepared) {
+                        self.discarded.extend(prepared.position);
+                        self.discarded.push(position);
+                    }
+                }
+            }
+        }
+        self.scanned = end;
+        Ok(())
+    }
+
+    fn push(&mut self, commit: LoggedCommit) {
+        if let Some(position) = commit.position {
+            for (name, version) in commit.versions.iter() {
+                self.positions
+                    .entry(name.clone())
+                    .or_default()
+                    .insert(*version, position);
+            }
+        }
+        // the history file has it already when the log it was moved out of was not rewritten
+        let archived = commit.versions.iter().any(|(name, version)| {
+            self.archived
+                .get(name)
+                .is_some_and(|archived| version <= archived)
+        });
+        if !archived {
+            self.commits.push(commit);
+        }
+    }
+
+    /// The versions of the trees right after the commit at `idx` of [`History::commits`].
+    pub fn versions_at(&self, idx: usize) -> HashMap<String, u64> {
+        let mut versions = self.base.clone();
+        for commit in self.commits[..=idx].iter() {
+            versions.extend(commit.versions.iter().cloned());
+        }
+        versions
+    }
+
+    /// The history file keeping the commits from the one at `idx` on.
+    pub fn archive(&self, idx: usize) -> Archive {
+        Archive {
+            base: match idx.checked_sub(1) {
+                Some(last) => self.versions_at(last),
+                None => self.base.clone(),
+            },
+            commits: self.commits[idx..].to_vec(),
+        }
+    }
+}
+
+/// How long the versions behind [`Db::snapshot_at`] are kept. Compaction on open drops from the
+/// tree files the index records only older versions need, along with the values only they
+/// point to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Retention {
+    /// Keep every version.
+    #[default]
+    All,
+    /// Keep the versions of the last `n` commits.
+    Transactions(usize),
+}
+
+impl Retention {
+    /// The position of the oldest retained commit among `commits` logged ones.
+    pub fn horizon(&self, commits: usize) -> usize {
+        match self {
+            Retention::All => 0,
+            Retention::Transactions(n) => commits.saturating_sub(*n),
+        }
+    }
+}
+
+impl SnapshotTree {
+    /// The number of commits the tree had seen when the snapshot was taken.
+    pub fn version(&self) -> u64 {
+        self.state.version
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.indexes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.indexes.is_empty()
+    }
+
+    pub fn get<K>(&self, key: K) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let key = unsafe { std::str::from_utf8_unchecked(key.as_ref()) };
+        self.state
+            .indexes
+            .get(key)
+            .map(|index| self.public.value(index))
+            .transpose()
+    }
+
+    pub fn scan<K, R>(&self, keys: R) -> Result<Vec<IVec>>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.range(keys)
+            .map(|entry| entry.map(|(_, value)| value))
+            .collect()
+    }
+
+    /// Iterates over the `(key, value)` pairs within `keys`, reading values as they are reached.
+    pub fn range<K, R>(&self, keys: R) -> SnapshotIter<'_>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (lo, hi) = str_bounds(&keys);
+        let range = self.state.indexes.range::<str, _>((lo, hi));
+        SnapshotIter {
+            range,
+            public: &self.public,
+        }
+    }
+
+    pub fn iter(&self) -> SnapshotIter<'_> {
+        self.range::<&[u8], _>(..)
+    }
+}
+
+pub struct SnapshotIter<'a> {
+    pub range: Range<'a, String, Index>,
+    pub public: &'a PublicState,
+}
+
+impl<'a> Iterator for SnapshotIter<'a> {
+    type Item = Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().map(|(key, index)| {
+            let value = self.public.value(index)?;
+            Ok((IVec::from(key.as_bytes()), value))
+        })
+    }
+}
+
+impl<'a> DoubleEndedIterator for SnapshotIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.range.next_back().map(|(key, index)| {
+            let value = self.public.value(index)?;
+            Ok((IVec::from(key.as_bytes()), value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::db::{Config, Db, TRANSACTION_FILE};
+    use crate::error::Error;
+    use crate::ivec::IVec;
+    use crate::snapshot::Retention;
+    use crate::transaction::{Durability, PAGE_LEN};
+    use crate::tree::{LockGranularity, TransactionOptions};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_read_transaction() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let trees = db.start_transaction(["a", "b"]).unwrap();
+        for i in 0..10 {
+            trees.get(0).set(format!("k{i}"), format!("a{i}")).unwrap();
+            trees.get(1).set(format!("k{i}"), format!("b{i}")).unwrap();
+        }
+        trees.commit().unwrap();
+
+        let snapshot = db.read_transaction(["a", "b"]).unwrap();
+        // writers are not blocked by the snapshot, nor is the snapshot by them
+        let trees = db.start_transaction(["a", "b"]).unwrap();
+        let during = db.read_transaction(["a"]).unwrap();
+        trees.get(0).set("k0", "changed").unwrap();
+        trees.get(1).remove("k9").unwrap();
+        trees.commit().unwrap();
+
+        for snapshot in [&snapshot, &during] {
+            let a = snapshot.get(0);
+            assert_eq!(a.get("k0").unwrap(), Some("a0".into()));
+            assert_eq!(a.version(), 1);
+        }
+        let b = snapshot.tree("b").unwrap();
+        assert_eq!(b.get("k9").unwrap(), Some("b9".into()));
+        let entries: Vec<(IVec, IVec)> = b.iter().map(|entry| entry.unwrap()).collect();
+        assert_eq!(entries.len(), 10);
+        assert_eq!(entries[3], ("k3".into(), "b3".into()));
+        let values = b.scan("k2".."k4").unwrap();
+        assert_eq!(values, vec![IVec::from("b2"), IVec::from("b3")]);
+        let last = b.range::<&str, _>(..).next_back().unwrap().unwrap();
+        assert_eq!(last.0, IVec::from("k9"));
+
+        let fresh = db.read_transaction(["a", "b"]).unwrap();
+        assert_eq!(fresh.get(0).get("k0").unwrap(), Some("changed".into()));
+        assert_eq!(fresh.get(1).get("k9").unwrap(), None);
+        assert_eq!(fresh.get(1).version(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_at() {
+        let dir = tempdir().unwrap();
+        let mut ids = vec![];
+        {
+            let db = Db::open(dir.path()).unwrap();
+            for (i, durability) in [Durability::Flush, Durability::None, Durability::Flush]
+                .into_iter()
+                .enumerate()
+            {
+                let options = TransactionOptions {
+                    durability,
+                    ..Default::default()
+                };
+                let trees = db.start_transaction_with(["a"], options).unwrap();
+                trees.get(0).set("k", format!("v{i}")).unwrap();
+                trees.get(0).set(format!("k{i}"), "").unwrap();
+                ids.push(trees.commit().unwrap().transaction_id);
+            }
+            // not a commit
+            assert!(matches!(
+                db.snapshot_at(ids[0] - 1),
+                Err(Error::HistoryUnavailable(_))
+            ));
+        }
+        let db = Db::open(dir.path()).unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            let a = db.snapshot_at(*id).unwrap().tree("a").unwrap();
+            // older versions are rebuilt from the index records of the tree file
+            assert_eq!(a.get("k").unwrap(), Some(format!("v{i}").as_str().into()));
+            assert_eq!(a.len(), i + 2);
+            assert_eq!(a.version(), i as u64 + 1);
+        }
+        drop(db);
+
+        let config = Config {
+            retention: Retention::Transactions(1),
+            ..Default::default()
+        };
+        let db = Db::open_with(dir.path(), config).unwrap();
+        assert_eq!(db.retained_from().unwrap(), ids[2]);
+        assert!(matches!(
+            db.snapshot_at(ids[1]),
+            Err(Error::HistoryUnavailable(_))
+        ));
+        let a = db.snapshot_at(ids[2]).unwrap().tree("a").unwrap();
+        assert_eq!(a.get("k").unwrap(), Some("v2".into()));
+        drop(a);
+        drop(db);
+
+        // opening compacted the log down to the retained commit
+        let log = dir.path().join(TRANSACTION_FILE);
+        assert!(std::fs::metadata(&log).unwrap().len() < PAGE_LEN);
+        let db = Db::open(dir.path()).unwrap();
+        assert!(matches!(
+            db.snapshot_at(ids[1]),
+            Err(Error::HistoryUnavailable(_))
+        ));
+        let a = db.snapshot_at(ids[2]).unwrap().tree("a").unwrap();
+        assert_eq!(a.len(), 4);
+        let trees = db.start_transaction(["a"]).unwrap();
+        trees.get(0).set("k", "v3").unwrap();
+        assert!(trees.commit().unwrap().transaction_id > ids[2]);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dir = tempdir().unwrap();
+        let len = |name: &str| dir.path().join(name).metadata().unwrap().len();
+        let mut ids = vec![];
+        {
+            let db = Db::open(dir.path()).unwrap();
+            for i in 0..3 {
+                let trees = db.start_transaction(["a"]).unwrap();
+                trees.get(0).set("k", format!("v{i}").repeat(1000)).unwrap();
+                ids.push(trees.commit().unwrap().transaction_id);
+            }
+        }
+        let logged = std::fs::read(dir.path().join(TRANSACTION_FILE)).unwrap();
+        assert!(logged.len() as u64 > 2 * PAGE_LEN);
+
+        // every version has an index record, so the log goes even though all are kept
+        let db = Db::open(dir.path()).unwrap();
+        assert!(len(TRANSACTION_FILE) < PAGE_LEN);
+        drop(db);
+        let db = Db::open(dir.path()).unwrap();
+        for (i, id) in ids.iter().enumerate() {
+            let a = db.snapshot_at(*id).unwrap().tree("a").unwrap();
+            let value = format!("v{i}").repeat(1000);
+            assert_eq!(a.get("k").unwrap(), Some(value.as_str().into()));
+        }
+        drop(db);
+
+        // as if compaction stopped before the log was replaced, the commits are not counted twice
+        std::fs::write(dir.path().join(TRANSACTION_FILE), logged).unwrap();
+        let config = Config {
+            retention: Retention::Transactions(4),
+            ..Default::default()
+        };
+        let db = Db::open_with(dir.path(), config).unwrap();
+        assert_eq!(db.retained_from().unwrap(), ids[0]);
+        drop(db);
+
+        // the versions before the retained ones leave the tree file, along with their values
+        let tree_len = len("a.tree");
+        let config = Config {
+            retention: Retention::Transactions(1),
+            ..Default::default()
+        };
+        let db = Db::open_with(dir.path(), config).unwrap();
+        assert!(len("a.tree") < tree_len - 3000);
+        assert!(matches!(
+            db.snapshot_at(ids[1]),
+            Err(Error::HistoryUnavailable(_))
+        ));
+        let a = db.snapshot_at(ids[2]).unwrap().tree("a").unwrap();
+        assert_eq!(a.get("k").unwrap(), Some("v2".repeat(1000).as_str().into()));
+        drop(a);
+        let trees = db.start_transaction(["a"]).unwrap();
+        trees.get(0).set("k2", "v3").unwrap();
+        let id = trees.commit().unwrap().transaction_id;
+        drop(trees);
+        drop(db);
+
+        let db = Db::open(dir.path()).unwrap();
+        let a = db.snapshot_at(ids[2]).unwrap().tree("a").unwrap();
+        assert_eq!(a.len(), 1);
+        let a = db.snapshot_at(id).unwrap().tree("a").unwrap();
+        assert_eq!(a.get("k").unwrap(), Some("v2".repeat(1000).as_str().into()));
+        assert_eq!(a.get("k2").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn test_snapshot_in_commit_order() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let options = TransactionOptions {
+            granularity: LockGranularity::Key,
+            ..Default::default()
+        };
+        // takes its id first, commits last
+        let late = db
+            .start_transaction_with(["a", "b"], options.clone())
+            .unwrap();
+        late.get(1).set("k", "late").unwrap();
+        late.id();
+        // which it does not wait for
+        let options = TransactionOptions {
+            durability: Durability::None,
+            ..options
+        };
+        let early = db.start_transaction_with(["a"], options).unwrap();
+        early.get(0).set("k", "early").unwrap();
+        let early = early.commit().unwrap().transaction_id;
+        late.get(0).set("k", "late").unwrap();
+        let late = late.commit().unwrap().transaction_id;
+        assert!(late < early);
+
+        let snapshot = db.snapshot_at(late).unwrap();
+        assert_eq!(snapshot.tree("a").unwrap().version(), 2);
+        assert_eq!(snapshot.tree("b").unwrap().version(), 1);
+        // the commit logged first misses the one logged after it, despite its larger id
+        let snapshot = db.snapshot_at(early).unwrap();
+        let a = snapshot.tree("a").unwrap();
+        assert_eq!(a.version(), 1);
+        assert_eq!(a.get("k").unwrap(), Some("early".into()));
+        assert!(snapshot.tree("b").unwrap().is_empty());
+    }
+}
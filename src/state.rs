@@ -1,51 +1,337 @@
+use crate::codec::{crc32, decode_delta, decode_indexes, encode_delta, encode_indexes, Delta};
 use crate::db::Cache;
-use crate::lock::Lock;
+use crate::ivec::IVec;
+use crate::lock::{KeyLocks, KeyRange, Lock};
 
-use crate::transaction::PAGE_LEN;
+use crate::transaction::{TreeWrites, PAGE_LEN};
 use crate::utils::First;
 
-use crate::Result;
+use crate::error::CorruptionKind;
+use crate::{Error, Result};
 use serde::{Deserialize, Serialize};
 use spin::{Mutex, RwLock};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, DerefMut};
 
 use std::sync::Arc;
-use std::usize;
 
 pub struct State {
     pub writer: Mutex<VersionedState>,
+    pub reads: Mutex<ReadSet>,
     pub public: PublicState,
+    /// The version this state keeps in [`PublicState::pins`], if any.
+    pub pin: Option<u64>,
+    /// The published state a pinned writer started from, which its reads keep seeing until it
+    /// commits.
+    pub base: Option<Arc<VersionedState>>,
 }
 
 #[derive(Clone)]
 pub struct PublicState {
-    pub reader: Arc<RwLock<VersionedState>>,
+    pub reader: Arc<RwLock<Arc<VersionedState>>>,
     pub cache: Arc<RwLock<Cache>>,
     pub lock: Arc<Lock>,
+    pub keys: Arc<KeyLocks>,
+    pub file: Arc<RwLock<File>>,
+    pub index_log: Arc<Mutex<IndexLog>>,
+    /// The name of the tree file, for corruption errors.
+    pub file_name: Arc<String>,
+    /// The published versions optimistic transactions started from, with how many did. Reads
+    /// made against them are validated with [`VersionedState::versions`].
+    pub pins: Arc<Mutex<BTreeMap<u64, usize>>>,
+    /// The keys removed by each version whose entries in [`VersionedState::versions`] are still
+    /// to be pruned.
+    pub removals: Arc<Mutex<BTreeMap<u64, Vec<String>>>>,
+    /// Why the index records of a commit could not be appended to the tree file, if they
+    /// could not. See [`PublicState::poison`].
+    pub poisoned: Arc<Mutex<Option<String>>>,
 }
 
-// impl State{
-//     pub fn folk(&self)->Self{
-//         Self{
-//             writer: Mutex::new(self.reader.lock().clone()),
-//             reader: self.reader.clone()
-//         }
-//     }
-// }
+impl PublicState {
+    /// The published state as of now. Later commits replace the state instead of changing it,
+    /// so the snapshot stays valid for as long as it is held.
+    pub fn snapshot(&self) -> Arc<VersionedState> {
+        self.reader.read().clone()
+    }
 
-#[derive(Clone)]
+    /// Refuses every later write to the tree after `err` kept the index records of a published
+    /// state from the tree file. The commits they belong to are in the log, and opening the
+    /// database again replays them into the tree.
+    pub fn poison(&self, err: Error) {
+        let mut poisoned = self.poisoned.lock();
+        if poisoned.is_none() {
+            *poisoned = Some(err.to_string());
+        }
+    }
+
+    /// Fails with [`Error::Poisoned`] once the tree is poisoned.
+    pub fn check_poisoned(&self) -> Result<()> {
+        match self.poisoned.lock().as_ref() {
+            Some(reason) => Err(Error::Poisoned(self.file_name.to_string(), reason.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the value behind `index`, serving it from the cache when possible.
+    pub fn value(&self, index: &Index) -> Result<IVec> {
+        let mut cache = self.cache.write();
+        if let Some(value) = cache.get(&(index.offset as usize)) {
+            return Ok(value.clone());
+        }
+        drop(cache);
+        let mut file = self.file.write();
+        let mut retriever = DataRetriever {
+            file: file.deref_mut(),
+            offset: index.offset,
+            length: index.length,
+            checksum: index.checksum,
+        };
+        let value = retriever
+            .retrieve()
+            .map_err(|err| err.in_file(&self.file_name))?;
+        drop(file);
+        self.cache
+            .write()
+            .insert(index.offset as usize, value.clone());
+        Ok(value)
+    }
+
+    /// Notes the keys `writes` removed, then drops from `state`, about to be published, the
+    /// versions of removed keys that no transaction could validate a read against anymore: the
+    /// versions every pinned state has seen, and that an index record covers.
+    pub fn prune(&self, state: &mut VersionedState, writes: &TreeWrites) {
+        let mut removals = self.removals.lock();
+        let removed = writes.removed();
+        if !removed.is_empty() {
+            removals.insert(writes.version, removed);
+        }
+        let persisted = self.index_log.lock().version.unwrap_or(0);
+        let pinned = self.pins.lock().keys().next().cloned();
+        // a transaction starting right now still gets the state before this one
+        let horizon = pinned
+            .unwrap_or(u64::MAX)
+            .min(writes.version - 1)
+            .min(persisted);
+        while let Some(entry) = removals.first_entry() {
+            if *entry.key() > horizon {
+                break;
+            }
+            let (version, keys) = entry.remove_entry();
+            for key in keys {
+                if state.versions.get(&key) == Some(&version) && !state.indexes.contains_key(&key) {
+                    state.versions.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    /// Gives a transaction its own copy of the published state to write to.
+    pub fn new(public: PublicState) -> Self {
+        let writer = VersionedState::clone(&public.snapshot());
+        Self {
+            writer: Mutex::new(writer),
+            reads: Mutex::new(ReadSet::default()),
+            public,
+            pin: None,
+            base: None,
+        }
+    }
+
+    /// Like [`State::new`], keeping the versions of removed keys the state may validate reads
+    /// against until it is dropped.
+    pub fn pinned(public: PublicState) -> Self {
+        let mut pins = public.pins.lock();
+        let base = public.snapshot();
+        let writer = VersionedState::clone(&base);
+        *pins.entry(writer.version).or_default() += 1;
+        drop(pins);
+        Self {
+            pin: Some(writer.version),
+            base: Some(base),
+            writer: Mutex::new(writer),
+            reads: Mutex::new(ReadSet::default()),
+            public,
+        }
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        let Some(version) = self.pin else {
+            return;
+        };
+        let mut pins = self.public.pins.lock();
+        if let Some(count) = pins.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&version);
+            }
+        }
+    }
+}
+
+/// What an optimistic transaction has read from a tree: single keys with the version they had,
+/// and key ranges.
+#[derive(Clone, Default)]
+pub struct ReadSet {
+    pub keys: BTreeMap<String, u64>,
+    pub ranges: Vec<(Bound<String>, Bound<String>)>,
+}
+
+impl ReadSet {
+    /// Checks the reads, made against `version` of the tree, against the current state and
+    /// returns a key that has changed since.
+    pub fn validate(&self, version: u64, current: &VersionedState) -> Option<String> {
+        if current.version == version {
+            return None;
+        }
+        for key in self.keys.keys() {
+            if current.key_version(key) > version {
+                return Some(key.clone());
+            }
+        }
+        for range in self.ranges.iter() {
+            if let Some((key, _)) = current
+                .versions
+                .range::<String, _>(range.clone())
+                .find(|(_, key_version)| **key_version > version)
+            {
+                return Some(key.clone());
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct VersionedState {
     pub indexes: BTreeMap<String, Index>,
+    /// Number of commits applied to the tree.
+    pub version: u64,
+    /// The version each key was last written at, removed keys included until
+    /// [`PublicState::prune`] drops them. Keys untouched since the tree was opened are left out
+    /// and count as version 0.
+    pub versions: BTreeMap<String, u64>,
+    /// Keys changed by the running transaction, with `None` for removals.
+    pub writes: BTreeMap<String, Option<Index>>,
     pub dirty: bool,
 }
 
+impl VersionedState {
+    pub fn key_version(&self, key: &str) -> u64 {
+        self.versions.get(key).cloned().unwrap_or(0)
+    }
+
+    /// Replaces the entries within `range` with those of `current`, except for the keys the
+    /// running transaction wrote.
+    pub fn sync(&mut self, current: &VersionedState, range: &KeyRange) {
+        let stale: Vec<String> = self
+            .indexes
+            .range::<String, _>(range.clone())
+            .map(|(key, _)| key.clone())
+            .filter(|key| !self.writes.contains_key(key))
+            .collect();
+        for key in stale {
+            self.indexes.remove(&key);
+        }
+        for (key, index) in current.indexes.range::<String, _>(range.clone()) {
+            if !self.writes.contains_key(key) {
+                self.indexes.insert(key.clone(), index.clone());
+            }
+        }
+    }
+
+    /// Moves the writes of the running transaction on top of `current`, the state published
+    /// since this one was copied.
+    pub fn rebase(&mut self, current: &VersionedState) {
+        let mut rebased = current.clone();
+        for (key, index) in self.writes.iter() {
+            match index {
+                Some(index) => rebased.indexes.insert(key.clone(), index.clone()),
+                None => rebased.indexes.remove(key),
+            };
+        }
+        rebased.writes = std::mem::take(&mut self.writes);
+        rebased.dirty = self.dirty;
+        *self = rebased;
+    }
+
+    pub fn insert(&mut self, key: String, index: Index) {
+        self.writes.insert(key.clone(), Some(index.clone()));
+        self.indexes.insert(key, index);
+        self.dirty = true;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Index> {
+        self.writes.insert(key.to_owned(), None);
+        self.dirty = true;
+        self.indexes.remove(key)
+    }
+
+    /// Replays the writes a committed transaction made to this tree.
+    pub fn apply(&mut self, writes: &TreeWrites) {
+        for (key, index) in writes.writes.iter() {
+            match index {
+                Some(index) => self.indexes.insert(key.clone(), index.clone()),
+                None => self.indexes.remove(key),
+            };
+            self.versions.insert(key.clone(), writes.version);
+        }
+        self.version = writes.version;
+    }
+
+    /// The write set of the running transaction, as it would be published.
+    pub fn pending(&self, name: &str) -> TreeWrites {
+        TreeWrites {
+            name: name.to_owned(),
+            version: self.version + 1,
+            writes: self
+                .writes
+                .iter()
+                .map(|(key, index)| (key.clone(), index.clone()))
+                .collect(),
+        }
+    }
+
+    /// Takes the write set of the running transaction and turns the state into the published
+    /// state of the next version.
+    pub fn publish(&mut self, name: &str) -> TreeWrites {
+        self.version += 1;
+        self.dirty = false;
+        for key in self.writes.keys() {
+            self.versions.insert(key.clone(), self.version);
+        }
+        TreeWrites {
+            name: name.to_owned(),
+            version: self.version,
+            writes: std::mem::take(&mut self.writes).into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RecoveredState {
+    Versioned {
+        version: u64,
+        indexes: BTreeMap<String, Index>,
+    },
+    Unversioned(BTreeMap<String, Index>),
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Index {
     pub offset: u64,
     pub length: u64,
+    /// CRC-32 of the value, missing for values written before values had one. Those values
+    /// also lay out their data pages the older way, see [`data_position`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<u32>,
 }
 
 pub struct StateBuilder {
@@ -54,93 +340,619 @@ pub struct StateBuilder {
 
 impl StateBuilder {
     pub fn build(&self) -> Result<VersionedState> {
-        let indexes = self.recover()?;
-        Ok(VersionedState {
+        Ok(self.build_with_log()?.0)
+    }
+
+    pub fn recover(&self) -> Result<BTreeMap<String, Index>> {
+        Ok(self.recover_versioned()?.1)
+    }
+
+    /// Loads the index of the file along with the version of its last record.
+    pub fn recover_versioned(&self) -> Result<(u64, BTreeMap<String, Index>)> {
+        self.recover_at(u64::MAX)
+    }
+
+    /// Like [`StateBuilder::build`], along with where the index records of the file stand and
+    /// the torn record at its end, if any.
+    pub fn build_with_log(&self) -> Result<(VersionedState, IndexLog, Option<TornRecord>)> {
+        let loaded = self.load(u64::MAX)?;
+        let state = Self::versioned(loaded.version, loaded.indexes);
+        Ok((state, loaded.log, loaded.torn))
+    }
+
+    /// Like [`StateBuilder::build`], from the index records written at `version` or before.
+    pub fn build_at(&self, version: u64) -> Result<VersionedState> {
+        let (version, indexes) = self.recover_at(version)?;
+        Ok(Self::versioned(version, indexes))
+    }
+
+    fn versioned(version: u64, indexes: BTreeMap<String, Index>) -> VersionedState {
+        VersionedState {
             indexes,
+            version,
+            versions: BTreeMap::new(),
+            writes: BTreeMap::new(),
             dirty: false,
-        })
+        }
     }
 
-    pub fn recover(&self) -> Result<BTreeMap<String, Index>> {
+    /// Loads the index of the file as of the last record written at `version` or before.
+    /// Records are appended and never overwritten, so every version written to the file can be
+    /// found.
+    pub fn recover_at(&self, version: u64) -> Result<(u64, BTreeMap<String, Index>)> {
+        let loaded = self.load(version)?;
+        Ok((loaded.version, loaded.indexes))
+    }
+
+    /// The index records written after `version`, oldest first. A last record that cannot be
+    /// read is skipped, as when the tree is opened.
+    pub fn records_after(&self, version: u64) -> Result<Vec<IndexRecord>> {
+        let mut file = self.file.write();
+        let mut position = file.metadata()?.len().div_ceil(PAGE_LEN) * PAGE_LEN;
+        let mut buf = [0_u8; 1];
+        let mut records = vec![];
+        let mut tail = true;
+        while let Some(start) = position.checked_sub(PAGE_LEN) {
+            position = start;
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut buf[..])?;
+            let record = match buf[0] {
+                DELTA => Self::read_delta(file.deref_mut(), start).map(IndexRecord::Delta),
+                JSON_SNAPSHOT | SNAPSHOT => Self::read_snapshot(file.deref_mut(), start, buf[0])
+                    .map(|(version, indexes)| IndexRecord::Snapshot(version, indexes)),
+                CONTINUATION | DATA => continue,
+                kind => return Err(Error::corruption(start, CorruptionKind::PageType(kind))),
+            };
+            let record = match record {
+                Ok(record) => record,
+                Err(Error::Corruption {
+                    kind: CorruptionKind::Truncated,
+                    ..
+                }) if tail => {
+                    tail = false;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            tail = false;
+            if record.version() <= version {
+                break;
+            }
+            records.push(record);
+        }
+        records.reverse();
+        Ok(records)
+    }
+
+    /// The version of the first index record of the file, `None` if it has none.
+    pub fn first_version(&self) -> Result<Option<u64>> {
         let mut file = self.file.write();
         let len = file.metadata()?.len();
-        let mut len = ((len + PAGE_LEN - 1) / PAGE_LEN) * PAGE_LEN;
-        if len == 0 {
-            Ok(BTreeMap::new())
-        } else {
-            let mut buf = [0_u8; 1];
-            // find data header
-            while let Some(position) = len.checked_sub(PAGE_LEN as u64) {
-                len = position;
-                file.seek(SeekFrom::Start(position))?;
-                file.read_exact(&mut buf[..])?;
-                if buf[0] == 1_u8 {
-                    break;
+        let mut buf = [0_u8; 1];
+        let mut position = 0;
+        while position < len {
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut buf[..])?;
+            match buf[0] {
+                DELTA => return Ok(Some(Self::read_delta(file.deref_mut(), position)?.0)),
+                JSON_SNAPSHOT | SNAPSHOT => {
+                    let (version, _) = Self::read_snapshot(file.deref_mut(), position, buf[0])?;
+                    return Ok(Some(version));
                 }
+                _ => position += PAGE_LEN,
             }
-            let mut buf = [0_u8; 4];
+        }
+        Ok(None)
+    }
+
+    /// Writes to `out` a checkpoint of the index as of the last record at `floor` or before,
+    /// then the records written after it, along with the values they point to. The records
+    /// before are left behind, and with them the values nothing else points to.
+    pub fn compact(&self, floor: u64, out: &mut File, file_name: Arc<String>) -> Result<()> {
+        let (version, indexes) = self.recover_at(floor)?;
+        let mut records = vec![IndexRecord::Snapshot(version, indexes)];
+        records.extend(self.records_after(version)?);
+        // a value is copied once, however many records point to it; an empty value shares its
+        // offset with the value written after it
+        let mut moved: HashMap<(u64, u64), Index> = HashMap::new();
+        for record in records.iter_mut() {
+            for index in record.indexes_mut() {
+                let copied = match moved.get(&(index.offset, index.length)) {
+                    Some(copied) => copied.clone(),
+                    None => {
+                        let reader =
+                            ValueReader::new(self.file.clone(), file_name.clone(), index.clone());
+                        let copied = StreamWriter { file: out, reader }.write()?;
+                        moved.insert((index.offset, index.length), copied.clone());
+                        copied
+                    }
+                };
+                *index = copied;
+            }
+        }
+        for record in records.iter() {
+            record.write(out)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the last checkpoint taken at `version` or before and replays the deltas written
+    /// after it, up to `version`. A last record that cannot be read is taken for one a crash
+    /// left half written, and skipped.
+    fn load(&self, version: u64) -> Result<Loaded> {
+        let mut file = self.file.write();
+        let file_len = file.metadata()?.len();
+        let mut len = file_len.div_ceil(PAGE_LEN) * PAGE_LEN;
+        let mut buf = [0_u8; 1];
+        let mut last = None;
+        let mut torn = None;
+        let mut deltas = vec![];
+        // find data header
+        let (mut base, mut indexes, kind) = loop {
+            let Some(position) = len.checked_sub(PAGE_LEN) else {
+                // nothing but values written by transactions that never committed
+                break (0, BTreeMap::new(), None);
+            };
+            len = position;
+            file.seek(SeekFrom::Start(position))?;
             file.read_exact(&mut buf[..])?;
-            let mut total = u32::from_be_bytes(buf) as u64;
-            let mut bytes = vec![0_u8; total as usize];
-            let mut first = First::new(5, 1);
-            let mut offset = 0;
-            while total > 0 {
-                if !first.first() {
-                    let mut buf = [1_u8; 1];
-                    file.read_exact(&mut buf[..])?;
-                    assert_eq!(buf[0], 0);
+            let tail = last.is_none() && torn.is_none();
+            // only a record cut short is taken for a torn one, a complete one is corrupt
+            let mut skip_torn = |err: Error| match err {
+                Error::Corruption {
+                    kind: kind @ CorruptionKind::Truncated,
+                    ..
+                } if tail => {
+                    torn = Some(TornRecord::new(position, file_len - position, kind));
+                    Ok(())
+                }
+                err => Err(err),
+            };
+            match buf[0] {
+                DELTA => {
+                    let delta = match Self::read_delta(file.deref_mut(), position) {
+                        Ok(delta) => delta,
+                        Err(err) => {
+                            skip_torn(err)?;
+                            continue;
+                        }
+                    };
+                    last.get_or_insert(delta.0);
+                    if delta.0 <= version {
+                        deltas.push(delta);
+                    }
                 }
-                let header_len = first.get();
-                let page_rest = PAGE_LEN - header_len;
-                let to_read = total.min(page_rest);
-                file.read_exact(&mut bytes[offset as usize..(offset + to_read) as usize])?;
-                total -= to_read;
-                offset += to_read;
+                JSON_SNAPSHOT | SNAPSHOT => {
+                    let (recovered, indexes) =
+                        match Self::read_snapshot(file.deref_mut(), position, buf[0]) {
+                            Ok(snapshot) => snapshot,
+                            Err(err) => {
+                                skip_torn(err)?;
+                                continue;
+                            }
+                        };
+                    last.get_or_insert(recovered);
+                    if recovered <= version {
+                        break (recovered, indexes, Some(buf[0]));
+                    }
+                }
+                CONTINUATION | DATA => {}
+                kind => return Err(Error::corruption(position, CorruptionKind::PageType(kind))),
+            }
+        };
+        let log = IndexLog {
+            version: last,
+            // a tree still indexed as JSON moves to the binary encoding on its next record, and
+            // one with a torn record keeps later deltas from having to be read past it
+            deltas: if torn.is_some() || kind == Some(JSON_SNAPSHOT) {
+                CHECKPOINT_INTERVAL
+            } else {
+                deltas.len()
+            },
+            changes: BTreeMap::new(),
+        };
+        for (version, entries) in deltas.into_iter().rev() {
+            if version <= base {
+                continue;
+            }
+            for (key, index) in entries {
+                match index {
+                    Some(index) => indexes.insert(key, index),
+                    None => indexes.remove(&key),
+                };
+            }
+            base = version;
+        }
+        Ok(Loaded {
+            version: base,
+            indexes,
+            log,
+            torn,
+        })
+    }
+
+    /// Reads the delta whose header byte was just read at `start`.
+    fn read_delta(file: &mut File, start: u64) -> Result<Delta> {
+        let (length, checksum) = Self::read_checked_header(file, start)?;
+        read_record(file, start, 13, length, 0, Some(checksum), |reader| {
+            decode_delta(reader)
+        })
+    }
+
+    /// Reads the index snapshot whose header byte `kind` was just read at `start`.
+    fn read_snapshot(
+        file: &mut File,
+        start: u64,
+        kind: u8,
+    ) -> Result<(u64, BTreeMap<String, Index>)> {
+        if kind == SNAPSHOT {
+            let (length, checksum) = Self::read_checked_header(file, start)?;
+            return read_record(file, start, 13, length, 0, Some(checksum), |reader| {
+                decode_indexes(reader)
+            });
+        }
+        let mut buf = [0_u8; 4];
+        read_header(file, start, &mut buf[..])?;
+        let length = u32::from_be_bytes(buf) as u64;
+        let recovered = read_record(file, start, 5, length, 0, None, |reader| {
+            Ok(serde_json::from_reader(reader)?)
+        })?;
+        Ok(match recovered {
+            RecoveredState::Versioned { version, indexes } => (version, indexes),
+            RecoveredState::Unversioned(indexes) => (0, indexes),
+        })
+    }
+
+    /// Reads the `u64` length and `u32` checksum following the header byte.
+    fn read_checked_header(file: &mut File, start: u64) -> Result<(u64, u32)> {
+        let mut buf = [0_u8; 12];
+        read_header(file, start, &mut buf[..])?;
+        let (length, checksum) = buf.split_at(8);
+        Ok((
+            u64::from_be_bytes(length.try_into().unwrap()),
+            u32::from_be_bytes(checksum.try_into().unwrap()),
+        ))
+    }
+}
+
+struct Loaded {
+    version: u64,
+    indexes: BTreeMap<String, Index>,
+    log: IndexLog,
+    torn: Option<TornRecord>,
+}
+
+/// An index record of a tree file, as read back.
+pub enum IndexRecord {
+    Snapshot(u64, BTreeMap<String, Index>),
+    Delta(Delta),
+}
+
+impl IndexRecord {
+    pub fn version(&self) -> u64 {
+        match self {
+            IndexRecord::Snapshot(version, _) => *version,
+            IndexRecord::Delta((version, _)) => *version,
+        }
+    }
+
+    fn indexes_mut(&mut self) -> Vec<&mut Index> {
+        match self {
+            IndexRecord::Snapshot(_, indexes) => indexes.values_mut().collect(),
+            IndexRecord::Delta((_, entries)) => entries
+                .iter_mut()
+                .filter_map(|(_, index)| index.as_mut())
+                .collect(),
+        }
+    }
+
+    /// Appends the record to `file`, encoded the current way.
+    fn write(&self, file: &mut File) -> Result<()> {
+        match self {
+            IndexRecord::Snapshot(version, indexes) => {
+                write_index_record(file, SNAPSHOT, &encode_indexes(*version, indexes))
+            }
+            IndexRecord::Delta((version, entries)) => {
+                let entries = entries
+                    .iter()
+                    .map(|(key, index)| (key.as_str(), index.as_ref()));
+                write_index_record(file, DELTA, &encode_delta(*version, entries))
+            }
+        }
+    }
+}
+
+/// A record at the end of a file that cannot be read, most likely because a crash stopped it
+/// from being written in full. Recovery falls back to the records before it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TornRecord {
+    /// Named by the caller, like the file of [`Error::Corruption`].
+    pub file: String,
+    pub offset: u64,
+    /// The bytes from the record to the end of the file.
+    pub length: u64,
+    pub kind: CorruptionKind,
+    pub truncated: bool,
+}
+
+impl TornRecord {
+    pub(crate) fn new(offset: u64, length: u64, kind: CorruptionKind) -> Self {
+        Self {
+            file: String::new(),
+            offset,
+            length,
+            kind,
+            truncated: false,
+        }
+    }
+
+    /// Cuts the record off `file`, unless pages other than its own were appended after it.
+    pub fn truncate(&mut self, file: &mut File) -> Result<()> {
+        let len = file.metadata()?.len();
+        let mut buf = [0_u8; 1];
+        let mut position = self.offset + PAGE_LEN;
+        while position < len {
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut buf[..])?;
+            if buf[0] != CONTINUATION {
+                return Ok(());
+            }
+            position += PAGE_LEN;
+        }
+        file.set_len(self.offset)?;
+        file.sync_data()?;
+        self.truncated = true;
+        Ok(())
+    }
+}
+
+/// Reads the header fields of the record starting at `start`, after its header byte.
+pub(crate) fn read_header(file: &mut File, start: u64, buf: &mut [u8]) -> Result<()> {
+    file.read_exact(buf).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => Error::corruption(start, CorruptionKind::Truncated),
+        _ => err.into(),
+    })
+}
+
+/// Feeds the `length` bytes of payload of the record starting at `start` to `decode`, then
+/// checks them against `checksum`, a CRC-32 continued from `seed`. The payload runs on from
+/// the `header_len` bytes of the header over continuation pages, each led by a zero byte.
+pub(crate) fn read_record<T, F>(
+    file: &mut File,
+    start: u64,
+    header_len: u64,
+    length: u64,
+    seed: u32,
+    checksum: Option<u32>,
+    decode: F,
+) -> Result<T>
+where
+    F: FnOnce(&mut dyn Read) -> Result<T>,
+{
+    let reader = RecordReader {
+        file,
+        position: start + header_len,
+        remaining: length,
+        page_rest: PAGE_LEN - header_len,
+        crc: seed,
+        corruption: None,
+    };
+    let mut reader = BufReader::with_capacity(PAGE_LEN as usize, reader);
+    let decoded = decode(&mut reader);
+    // the checksum covers whatever the decoder left unread as well
+    let left = std::io::copy(&mut reader, &mut std::io::sink());
+    let reader = reader.into_inner();
+    if let Some((offset, kind)) = reader.corruption {
+        return Err(Error::corruption(offset, kind));
+    }
+    let left = left?;
+    if checksum.is_some_and(|checksum| checksum != reader.crc) {
+        return Err(Error::corruption(start, CorruptionKind::Checksum));
+    }
+    match decoded {
+        Ok(decoded) if left == 0 => Ok(decoded),
+        _ => Err(Error::corruption(start, CorruptionKind::Encoding)),
+    }
+}
+
+/// Reads the payload of a record across its pages, see [`read_record`].
+pub(crate) struct RecordReader<'file> {
+    file: &'file mut File,
+    position: u64,
+    remaining: u64,
+    page_rest: u64,
+    crc: u32,
+    /// Set when the pages turn out not to hold the record.
+    corruption: Option<(u64, CorruptionKind)>,
+}
+
+impl RecordReader<'_> {
+    fn fill(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        self.file.read_exact(buf).inspect_err(|err| {
+            if err.kind() == ErrorKind::UnexpectedEof {
+                self.corruption = Some((self.position, CorruptionKind::Truncated));
+            }
+        })?;
+        self.position += buf.len() as u64;
+        Ok(())
+    }
+}
+
+impl Read for RecordReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.page_rest == 0 {
+            let mut header = [0_u8; 1];
+            self.fill(&mut header[..])?;
+            if header[0] != CONTINUATION {
+                let kind = CorruptionKind::PageType(header[0]);
+                self.corruption = Some((self.position - 1, kind));
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    kind.to_string(),
+                ));
             }
-            Ok(serde_json::from_slice(&bytes[..])?)
+            self.page_rest = PAGE_LEN - 1;
+        }
+        let len = (buf.len() as u64).min(self.page_rest).min(self.remaining) as usize;
+        self.fill(&mut buf[..len])?;
+        self.crc = crc32(self.crc, &buf[..len]);
+        self.page_rest -= len as u64;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+/// Header byte of the pages a record runs on over.
+const CONTINUATION: u8 = 0;
+/// Header byte of the pages holding values.
+const DATA: u8 = 2;
+/// Header byte of an index snapshot written as JSON with a `u32` length, as older versions
+/// did. Still read, never written.
+const JSON_SNAPSHOT: u8 = 1;
+/// Header byte of a checkpoint: a full index snapshot in the binary encoding of
+/// [`encode_indexes`], with a `u64` length and the `u32` CRC-32 of the snapshot.
+const SNAPSHOT: u8 = 3;
+/// Header byte of a delta: the entries changed since the previous record, in the encoding of
+/// [`encode_delta`], with a `u64` length and the `u32` CRC-32 of the delta.
+const DELTA: u8 = 4;
+
+/// Deltas written before the next checkpoint.
+pub const CHECKPOINT_INTERVAL: usize = 64;
+
+/// Where the index records of a tree file stand.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexLog {
+    /// The version of the last record, `None` if the file has none.
+    pub version: Option<u64>,
+    /// Deltas written since the last checkpoint.
+    pub deltas: usize,
+    /// The keys written by each published version after the last record.
+    pub changes: BTreeMap<u64, Vec<String>>,
+}
+
+impl IndexLog {
+    /// Remembers the keys `writes` changed, for the delta that records their version.
+    pub fn changed(&mut self, writes: &TreeWrites) {
+        let keys = writes.writes.iter().map(|(key, _)| key.clone()).collect();
+        self.changes.insert(writes.version, keys);
+    }
+
+    /// The keys changed after `version` up to `to`, unless the changes of a version in between
+    /// are unknown.
+    fn changed_since(&self, version: u64, to: u64) -> Option<Vec<&String>> {
+        let changes = self.changes.range(version + 1..=to);
+        if changes.clone().count() as u64 != to - version {
+            return None;
         }
+        let mut keys: Vec<&String> = changes.flat_map(|(_, keys)| keys).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        Some(keys)
     }
 }
 
+/// Appends the index of a tree to its file: a delta of the entries written since the last
+/// record, or a full checkpoint once [`CHECKPOINT_INTERVAL`] deltas were written or when the
+/// delta would cover half the tree.
 pub struct StateWriter<'a, 'file> {
     pub file: &'file mut File,
     pub state: &'a VersionedState,
+    pub log: &'a mut IndexLog,
 }
 
 impl<'a, 'file> StateWriter<'a, 'file> {
     pub fn write(&mut self) -> Result<()> {
-        let len = self.file.metadata()?.len();
-        let len = ((len + PAGE_LEN - 1) / PAGE_LEN) * PAGE_LEN;
-        self.file.set_len(len)?;
-        self.file.seek(SeekFrom::Start(len))?;
-        self.file.write(&[1_u8])?;
-        let data = serde_json::to_vec(&self.state.indexes)?;
-        let mut total = data.len();
-        self.file.write(&(total as u32).to_be_bytes()[..])?;
-        let mut offset = 0;
-        let mut first = First::new(5, 1);
-        while total > 0 {
-            if !first.first() {
-                self.file.write(&[0_u8])?;
-            }
-            let header_len = first.get();
-            let page_rest = PAGE_LEN - header_len;
-            let to_write = total.min(page_rest as usize);
-            self.file.write(&data[offset..offset + to_write])?;
-            offset += to_write;
-            total -= to_write;
-        }
+        let changed = match self.log.version {
+            // a later commit wrote its index first
+            Some(version) if self.state.version <= version => return Ok(()),
+            Some(version) if self.log.deltas < CHECKPOINT_INTERVAL => self
+                .log
+                .changed_since(version, self.state.version)
+                .filter(|changed| changed.len() * 2 <= self.state.indexes.len()),
+            _ => None,
+        };
+        let (kind, data) = match &changed {
+            Some(changed) => {
+                let entries = changed
+                    .iter()
+                    .map(|key| (key.as_str(), self.state.indexes.get(*key)));
+                (DELTA, encode_delta(self.state.version, entries))
+            }
+            None => (
+                SNAPSHOT,
+                encode_indexes(self.state.version, &self.state.indexes),
+            ),
+        };
+        let delta = changed.is_some();
+        write_index_record(self.file, kind, &data)?;
+        self.log.version = Some(self.state.version);
+        self.log.deltas = match delta {
+            true => self.log.deltas + 1,
+            false => 0,
+        };
+        self.log.changes = self.log.changes.split_off(&(self.state.version + 1));
         Ok(())
     }
 }
 
+/// Appends an index record of type `kind` holding `data` to `file`, from the start of the page
+/// after its end: the header byte, the length and CRC-32 of `data`, then `data` itself, running
+/// on over continuation pages.
+pub(crate) fn write_index_record(file: &mut File, kind: u8, data: &[u8]) -> Result<()> {
+    let len = file.metadata()?.len();
+    let len = len.div_ceil(PAGE_LEN) * PAGE_LEN;
+    file.set_len(len)?;
+    file.seek(SeekFrom::Start(len))?;
+    file.write_all(&[kind])?;
+    let mut total = data.len();
+    file.write_all(&(total as u64).to_be_bytes()[..])?;
+    file.write_all(&crc32(0, data).to_be_bytes()[..])?;
+    let mut offset = 0;
+    let mut first = First::new(13, 1);
+    while total > 0 {
+        if !first.first() {
+            file.write_all(&[0_u8])?;
+        }
+        let header_len = first.get();
+        let page_rest = PAGE_LEN - header_len;
+        let to_write = total.min(page_rest as usize);
+        file.write_all(&data[offset..offset + to_write])?;
+        offset += to_write;
+        total -= to_write;
+    }
+    Ok(())
+}
+
 pub struct DataWriter<'file> {
     pub file: &'file mut File,
-    pub data: Arc<Vec<u8>>,
+    pub data: IVec,
 }
 
 impl<'file> DataWriter<'file> {
-    pub fn write(&mut self) -> Result<u64> {
+    pub fn write(&mut self) -> Result<Index> {
+        let mut writer = StreamWriter {
+            file: &mut *self.file,
+            reader: &self.data[..],
+        };
+        writer.write()
+    }
+}
+
+pub struct StreamWriter<'file, R> {
+    pub file: &'file mut File,
+    pub reader: R,
+}
+
+impl<'file, R> StreamWriter<'file, R>
+where
+    R: Read,
+{
+    /// Appends everything `reader` yields to the data pages of the file, one page at a time,
+    /// and returns where the value landed.
+    pub fn write(&mut self) -> Result<Index> {
         let len = self.file.metadata()?.len();
         let page_offset = len / PAGE_LEN * PAGE_LEN;
         let mut buf = [0_u8; 1];
@@ -148,9 +960,9 @@ impl<'file> DataWriter<'file> {
             if page_offset != len && {
                 self.file.seek(SeekFrom::Start(page_offset))?;
                 self.file.read_exact(&mut buf[..])?;
-                buf[0] != 2
+                buf[0] != DATA
             } {
-                let new_offset = ((len + PAGE_LEN - 1) / PAGE_LEN) * PAGE_LEN;
+                let new_offset = len.div_ceil(PAGE_LEN) * PAGE_LEN;
                 self.file.set_len(new_offset)?;
                 self.file.seek(SeekFrom::Start(new_offset))?;
                 true
@@ -160,26 +972,126 @@ impl<'file> DataWriter<'file> {
             }
         };
         if need_header {
-            self.file.write(&[2_u8])?;
+            self.file.write_all(&[DATA])?;
+        }
+        let data_offset = self.file.stream_position()?;
+        let mut page_rest = PAGE_LEN - data_offset % PAGE_LEN;
+        let mut length = 0;
+        let mut checksum = 0;
+        let mut chunk = vec![0_u8; PAGE_LEN as usize];
+        loop {
+            let to_read = if page_rest == 0 {
+                PAGE_LEN - 1
+            } else {
+                page_rest
+            };
+            let read = read_full(&mut self.reader, &mut chunk[..to_read as usize])?;
+            if read == 0 {
+                break;
+            }
+            if page_rest == 0 {
+                self.file.write_all(&[DATA])?;
+                page_rest = PAGE_LEN - 1;
+            }
+            self.file.write_all(&chunk[..read])?;
+            checksum = crc32(checksum, &chunk[..read]);
+            page_rest -= read as u64;
+            length += read as u64;
         }
-        let data_offset = self.file.metadata()?.len();
-        let mut total = self.data.len();
-        let mut offset = 0;
-        let rest = ((data_offset + PAGE_LEN - 1) / PAGE_LEN) * PAGE_LEN - data_offset;
-        let mut first = First::new(rest, 1);
-        while total > 0 {
-            if !first.first() {
-                self.file.write(&[2_u8])?;
+
+        Ok(Index {
+            offset: data_offset,
+            length,
+            checksum: Some(checksum),
+        })
+    }
+}
+
+/// Reads until `buf` is full or `reader` is exhausted.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(read)
+}
+
+/// The bytes of a value stored before its first page header. A value fills the rest of the page
+/// it starts on. Values without a checksum were written by versions that instead took the part of
+/// the page before the value, running past the page end and putting the next header mid-page;
+/// they are still read that way.
+fn first_chunk(index: &Index) -> u64 {
+    let rest = PAGE_LEN - index.offset % PAGE_LEN;
+    match index.checksum {
+        Some(_) => rest,
+        None => PAGE_LEN - rest % PAGE_LEN,
+    }
+}
+
+/// Maps `position` inside the value of `index` to its position in the file, together with the
+/// number of bytes readable there before the next page header.
+pub fn data_position(index: &Index, position: u64) -> (u64, u64) {
+    let first = first_chunk(index);
+    if position < first {
+        (index.offset + position, first - position)
+    } else {
+        let position = position - first;
+        let page = position / (PAGE_LEN - 1);
+        let in_page = position % (PAGE_LEN - 1);
+        (
+            index.offset + first + page * PAGE_LEN + 1 + in_page,
+            PAGE_LEN - 1 - in_page,
+        )
+    }
+}
+
+/// Whether `position` inside the value of `index` comes right after a page header.
+fn after_header(index: &Index, position: u64) -> bool {
+    let first = first_chunk(index);
+    position >= first && (position - first).is_multiple_of(PAGE_LEN - 1)
+}
+
+/// Reads the part of a value starting at `position` into `buf`, checking the header of every
+/// page it enters.
+fn read_data(file: &mut File, index: &Index, position: u64, buf: &mut [u8]) -> Result<usize> {
+    let to_read = (buf.len() as u64).min(index.length.saturating_sub(position));
+    let mut read = 0;
+    while read < to_read {
+        let (physical, rest) = data_position(index, position + read);
+        if after_header(index, position + read) {
+            let mut header = [0_u8; 1];
+            file.seek(SeekFrom::Start(physical - 1))?;
+            read_header(file, index.offset, &mut header[..])?;
+            if header[0] != DATA {
+                let kind = CorruptionKind::PageType(header[0]);
+                return Err(Error::corruption(physical - 1, kind));
             }
-            let header_len = first.get();
-            let page_rest = PAGE_LEN - header_len;
-            let to_write = total.min(page_rest as usize);
-            self.file.write(&self.data[offset..offset + to_write])?;
-            offset += to_write;
-            total -= to_write;
+        } else {
+            file.seek(SeekFrom::Start(physical))?;
         }
+        let len = rest.min(to_read - read);
+        read_header(
+            file,
+            index.offset,
+            &mut buf[read as usize..(read + len) as usize],
+        )?;
+        read += len;
+    }
+    Ok(read as usize)
+}
 
-        Ok(data_offset)
+/// Fails if `value` does not match the checksum of `index`.
+fn verify(index: &Index, value: &[u8]) -> Result<()> {
+    match index.checksum {
+        Some(checksum) if crc32(0, value) != checksum => {
+            Err(Error::corruption(index.offset, CorruptionKind::Checksum))
+        }
+        _ => Ok(()),
     }
 }
 
@@ -187,38 +1099,199 @@ pub struct DataRetriever<'file> {
     pub file: &'file mut File,
     pub offset: u64,
     pub length: u64,
+    pub checksum: Option<u32>,
 }
 
 impl<'file> DataRetriever<'file> {
-    pub fn retrieve(&mut self) -> Result<Vec<u8>> {
-        self.file.seek(SeekFrom::Start(self.offset))?;
-        let mut total = self.length;
-        let mut bytes = vec![0_u8; total as usize];
-        let rest = ((self.offset + PAGE_LEN - 1) / PAGE_LEN) * PAGE_LEN - self.offset;
-        let mut first = First::new(rest, 1);
-        let mut offset = 0;
-        while total > 0 {
-            if !first.first() {
-                let mut buf = [1_u8; 1];
-                self.file.read_exact(&mut buf[..])?;
-                assert_eq!(buf[0], 2);
+    pub fn retrieve(&mut self) -> Result<IVec> {
+        let index = Index {
+            offset: self.offset,
+            length: self.length,
+            checksum: self.checksum,
+        };
+        let mut bytes = vec![0_u8; self.length as usize];
+        read_data(self.file, &index, 0, &mut bytes[..])?;
+        verify(&index, &bytes[..])?;
+        Ok(bytes.into())
+    }
+}
+
+/// Values closer than this are read together, paying for the gap instead of another seek.
+pub const COALESCE_GAP: u64 = PAGE_LEN;
+/// Upper bound on the size of a single coalesced read.
+pub const COALESCE_LIMIT: u64 = 1 << 20;
+
+/// Reads many values with as few file reads as possible: the values are visited in file order
+/// and neighbours are fetched by one contiguous read, then cut back out of the buffer.
+pub struct BatchRetriever<'file, 'a> {
+    pub file: &'file mut File,
+    pub indexes: &'a [Index],
+}
+
+impl<'file, 'a> BatchRetriever<'file, 'a> {
+    /// Returns the values in the order of `indexes`.
+    pub fn retrieve(&mut self) -> Result<Vec<IVec>> {
+        let mut order: Vec<usize> = (0..self.indexes.len()).collect();
+        order.sort_by_key(|i| self.indexes[*i].offset);
+        let mut values: Vec<Option<IVec>> = vec![None; self.indexes.len()];
+        let mut run_start = 0;
+        while run_start < order.len() {
+            let start = self.indexes[order[run_start]].offset;
+            let mut end = Self::physical_end(&self.indexes[order[run_start]]);
+            let mut run_end = run_start + 1;
+            while run_end < order.len() {
+                let index = &self.indexes[order[run_end]];
+                let index_end = Self::physical_end(index);
+                if index.offset > end + COALESCE_GAP || index_end.max(end) - start > COALESCE_LIMIT
+                {
+                    break;
+                }
+                end = end.max(index_end);
+                run_end += 1;
+            }
+            let mut buf = vec![0_u8; (end - start) as usize];
+            self.file.seek(SeekFrom::Start(start))?;
+            read_header(self.file, start, &mut buf[..])?;
+            for i in &order[run_start..run_end] {
+                let index = &self.indexes[*i];
+                values[*i] = Some(Self::cut(&buf[..], start, index)?);
+            }
+            run_start = run_end;
+        }
+        Ok(values.into_iter().map(|value| value.unwrap()).collect())
+    }
+
+    /// The file position right after the last byte of the value.
+    fn physical_end(index: &Index) -> u64 {
+        if index.length == 0 {
+            index.offset
+        } else {
+            data_position(index, index.length - 1).0 + 1
+        }
+    }
+
+    /// Copies the value out of `buf`, which holds the file content starting at `start`.
+    fn cut(buf: &[u8], start: u64, index: &Index) -> Result<IVec> {
+        let mut value = Vec::with_capacity(index.length as usize);
+        while (value.len() as u64) < index.length {
+            let (physical, rest) = data_position(index, value.len() as u64);
+            if after_header(index, value.len() as u64) {
+                let header = buf[(physical - 1 - start) as usize];
+                if header != DATA {
+                    let kind = CorruptionKind::PageType(header);
+                    return Err(Error::corruption(physical - 1, kind));
+                }
+            }
+            let len = rest.min(index.length - value.len() as u64);
+            let from = (physical - start) as usize;
+            value.extend_from_slice(&buf[from..from + len as usize]);
+        }
+        verify(index, &value[..])?;
+        Ok(value.into())
+    }
+}
+
+/// A `Read + Seek` view of a single value that walks its data pages on demand, so that large
+/// values never have to be held in memory at once. A value read from start to end in one go is
+/// checked against its checksum once the last byte is read.
+pub struct ValueReader {
+    pub file: Arc<RwLock<File>>,
+    /// The name of the tree file, for corruption errors.
+    pub file_name: Arc<String>,
+    pub index: Index,
+    pub position: u64,
+    /// How far the value was read in order from its start, and the checksum of that part.
+    checked: Option<(u64, u32)>,
+}
+
+impl ValueReader {
+    pub fn new(file: Arc<RwLock<File>>, file_name: Arc<String>, index: Index) -> Self {
+        Self {
+            file,
+            file_name,
+            index,
+            position: 0,
+            checked: Some((0, 0)),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.index.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.length == 0
+    }
+
+    /// Turns `err` into the error of a `Read` or `Seek` call, keeping it as the inner error.
+    fn io_error(&self, err: Error) -> std::io::Error {
+        match err {
+            Error::IO(err) => err,
+            err @ Error::Corruption { .. } => {
+                std::io::Error::new(ErrorKind::InvalidData, err.in_file(&self.file_name))
+            }
+            err => std::io::Error::other(err),
+        }
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut file = self.file.write();
+        let read = read_data(file.deref_mut(), &self.index, self.position, buf)
+            .map_err(|err| self.io_error(err))?;
+        if self.position == 0 {
+            self.checked = Some((0, 0));
+        }
+        self.checked = match self.checked {
+            Some((checked, crc)) if checked == self.position => {
+                Some((checked + read as u64, crc32(crc, &buf[..read])))
+            }
+            _ => None,
+        };
+        self.position += read as u64;
+        if let (Some((checked, crc)), Some(checksum)) = (self.checked, self.index.checksum) {
+            if checked == self.index.length && read > 0 && crc != checksum {
+                let err = Error::corruption(self.index.offset, CorruptionKind::Checksum);
+                return Err(self.io_error(err));
             }
-            let header_len = first.get();
-            let page_rest = PAGE_LEN - header_len;
-            let to_read = total.min(page_rest);
-            self.file
-                .read_exact(&mut bytes[offset as usize..(offset + to_read) as usize])?;
-            total -= to_read;
-            offset += to_read;
         }
-        Ok(bytes)
+        Ok(read)
+    }
+}
+
+impl Seek for ValueReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.index.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::state::{DataRetriever, DataWriter, Index, StateBuilder, StateWriter};
+    use crate::error::{CorruptionKind, Error};
+    use crate::state::{
+        BatchRetriever, DataRetriever, DataWriter, Index, IndexLog, StateBuilder, StateWriter,
+        StreamWriter, ValueReader, VersionedState, CHECKPOINT_INTERVAL,
+    };
+    use crate::transaction::PAGE_LEN;
     use spin::RwLock;
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
     use std::ops::{Deref, DerefMut};
     use std::sync::Arc;
     use tempfile::tempfile;
@@ -236,20 +1309,18 @@ mod test {
 
             for i in 0..100 {
                 let value = format!("value{i}");
-                let length = value.len() as u64;
                 let mut data_writer = DataWriter {
                     file: file_guard.deref_mut(),
-                    data: Arc::new(value.into_bytes()),
+                    data: value.into(),
                 };
-                let offset = data_writer.write().unwrap();
-                state_writer
-                    .indexes
-                    .insert(format!("key{i}"), Index { offset, length });
+                let index = data_writer.write().unwrap();
+                state_writer.indexes.insert(format!("key{i}"), index);
             }
 
             let mut writer = StateWriter {
                 file: file_guard.deref_mut(),
                 state: state_writer.deref(),
+                log: &mut IndexLog::default(),
             };
             writer.write().unwrap();
         }
@@ -262,10 +1333,293 @@ mod test {
                     file: file_guard.deref_mut(),
                     offset: index.offset,
                     length: index.length,
+                    checksum: index.checksum,
                 };
                 let data = retriever.retrieve().unwrap();
                 assert_eq!(*data, format!("value{i}").into_bytes());
             }
         }
     }
+
+    #[test]
+    fn test_legacy_layout() {
+        // what versions before checksums wrote: the first chunk as long as the part of the page
+        // before the value, then a header and a page worth of bytes at a time
+        let value: Vec<u8> = (0..3000_u32).map(|i| (i % 251) as u8).collect();
+        let offset = 6;
+        let mut file = tempfile().unwrap();
+        file.write_all(&[2_u8, 0, 0, 0, 0, 0]).unwrap();
+        let (first, mut rest) = value.split_at(offset as usize);
+        file.write_all(first).unwrap();
+        while !rest.is_empty() {
+            let (page, next) = rest.split_at(rest.len().min(PAGE_LEN as usize - 1));
+            file.write_all(&[2_u8]).unwrap();
+            file.write_all(page).unwrap();
+            rest = next;
+        }
+        let index = Index {
+            offset,
+            length: value.len() as u64,
+            checksum: None,
+        };
+        let mut retriever = DataRetriever {
+            file: &mut file,
+            offset,
+            length: index.length,
+            checksum: None,
+        };
+        assert_eq!(*retriever.retrieve().unwrap(), value[..]);
+        let indexes = [index.clone()];
+        let mut batch = BatchRetriever {
+            file: &mut file,
+            indexes: &indexes[..],
+        };
+        assert_eq!(*batch.retrieve().unwrap()[0], value[..]);
+        let file = Arc::new(RwLock::new(file));
+        let mut reader = ValueReader::new(file, Arc::new("legacy".to_owned()), index);
+        let mut buf = [0_u8; 100];
+        reader.seek(SeekFrom::Start(2000)).unwrap();
+        reader.read_exact(&mut buf[..]).unwrap();
+        assert_eq!(buf[..], value[2000..2100]);
+    }
+
+    #[test]
+    fn test_stream_value() {
+        let file = Arc::new(RwLock::new(tempfile().unwrap()));
+        let value: Vec<u8> = (0..5000_u32).map(|i| (i % 251) as u8).collect();
+        let (small, index) = {
+            let mut file_guard = file.write();
+            let mut data_writer = DataWriter {
+                file: file_guard.deref_mut(),
+                data: b"small".into(),
+            };
+            let small = data_writer.write().unwrap();
+            let mut stream_writer = StreamWriter {
+                file: file_guard.deref_mut(),
+                reader: &value[..],
+            };
+            (small, stream_writer.write().unwrap())
+        };
+        assert_eq!(index.length, value.len() as u64);
+
+        let name = Arc::new("value.tree".to_owned());
+        let mut reader = ValueReader::new(file.clone(), name.clone(), index.clone());
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, value);
+
+        let mut buf = [0_u8; 100];
+        reader.seek(SeekFrom::Start(1020)).unwrap();
+        reader.read_exact(&mut buf[..]).unwrap();
+        assert_eq!(buf[..], value[1020..1120]);
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        assert_eq!(reader.read(&mut buf[..]).unwrap(), 10);
+        assert_eq!(buf[..10], value[4990..]);
+
+        let mut file_guard = file.write();
+        let mut retriever = DataRetriever {
+            file: file_guard.deref_mut(),
+            offset: small.offset,
+            length: 5,
+            checksum: small.checksum,
+        };
+        assert_eq!(retriever.retrieve().unwrap(), b"small".to_vec());
+
+        // a corrupt value fails the read with the corruption error inside
+        file_guard
+            .seek(SeekFrom::Start(index.offset + 100))
+            .unwrap();
+        file_guard.write_all(&[!value[100]]).unwrap();
+        drop(file_guard);
+        let mut reader = ValueReader::new(file.clone(), name, index.clone());
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let inner = err.into_inner().unwrap().downcast::<Error>().unwrap();
+        assert!(matches!(
+            *inner,
+            Error::Corruption { ref file, offset, kind: CorruptionKind::Checksum }
+                if file == "value.tree" && offset == index.offset
+        ));
+    }
+
+    #[test]
+    fn test_json_snapshot() {
+        let file = Arc::new(RwLock::new(tempfile().unwrap()));
+        let builder = StateBuilder { file: file.clone() };
+        let json = br#"{"version":3,"indexes":{"a":{"offset":1,"length":2}}}"#;
+        {
+            let mut file_guard = file.write();
+            file_guard.write_all(&[1_u8]).unwrap();
+            file_guard
+                .write_all(&(json.len() as u32).to_be_bytes()[..])
+                .unwrap();
+            file_guard.write_all(&json[..]).unwrap();
+        }
+        let (mut state, mut log, _) = builder.build_with_log().unwrap();
+        assert_eq!(state.version, 3);
+        assert_eq!(state.indexes["a"].length, 2);
+
+        // the next snapshot moves the tree to the binary encoding
+        state.version += 1;
+        state.indexes.insert(
+            "b".into(),
+            Index {
+                offset: 3,
+                length: 4,
+                checksum: None,
+            },
+        );
+        let mut file_guard = file.write();
+        let mut writer = StateWriter {
+            file: file_guard.deref_mut(),
+            state: &state,
+            log: &mut log,
+        };
+        writer.write().unwrap();
+        let mut header = [0_u8; 1];
+        file_guard.seek(SeekFrom::Start(PAGE_LEN)).unwrap();
+        file_guard.read_exact(&mut header[..]).unwrap();
+        assert_eq!(header[0], 3);
+        drop(file_guard);
+        let (version, indexes) = builder.recover_versioned().unwrap();
+        assert_eq!(version, 4);
+        assert_eq!(indexes.len(), 2);
+        assert_eq!(builder.recover_at(3).unwrap().1.len(), 1);
+    }
+
+    #[test]
+    fn test_index_deltas() {
+        let file = Arc::new(RwLock::new(tempfile().unwrap()));
+        let builder = StateBuilder { file: file.clone() };
+        let (mut state, mut log, _) = builder.build_with_log().unwrap();
+        let commit = |state: &mut VersionedState, log: &mut IndexLog, keys: &[(&str, bool)]| {
+            for (key, set) in keys {
+                if *set {
+                    let length = state.version;
+                    let index = Index {
+                        offset: 1,
+                        length,
+                        checksum: None,
+                    };
+                    state.insert(key.to_string(), index);
+                } else {
+                    state.remove(key);
+                }
+            }
+            let writes = state.publish("tree");
+            log.changed(&writes);
+            let mut file_guard = file.write();
+            let before = file_guard.metadata().unwrap().len();
+            let mut writer = StateWriter {
+                file: file_guard.deref_mut(),
+                state,
+                log,
+            };
+            writer.write().unwrap();
+            file_guard.metadata().unwrap().len() - before.div_ceil(PAGE_LEN) * PAGE_LEN
+        };
+        let keys: Vec<String> = (0..500).map(|i| format!("key{i}")).collect();
+        let all: Vec<(&str, bool)> = keys.iter().map(|key| (key.as_str(), true)).collect();
+        assert!(commit(&mut state, &mut log, &all) > PAGE_LEN);
+        // a one-key update appends the key alone
+        assert!(commit(&mut state, &mut log, &[("key1", true)]) < 32);
+        commit(&mut state, &mut log, &[("key2", false)]);
+        assert_eq!(log.deltas, 2);
+
+        let (recovered, recovered_log, torn) = builder.build_with_log().unwrap();
+        assert!(torn.is_none());
+        assert_eq!(recovered_log, log);
+        assert_eq!(recovered.version, 3);
+        assert_eq!(recovered.indexes.len(), 499);
+        assert_eq!(recovered.indexes["key1"].length, 1);
+        let (version, indexes) = builder.recover_at(2).unwrap();
+        assert_eq!((version, indexes.len()), (2, 500));
+
+        for _ in 0..CHECKPOINT_INTERVAL {
+            commit(&mut state, &mut log, &[("key3", true)]);
+        }
+        assert!(log.deltas < CHECKPOINT_INTERVAL);
+        let recovered = builder.build().unwrap();
+        assert_eq!(recovered.version, state.version);
+        assert_eq!(recovered.indexes["key3"].length, state.version - 1);
+
+        // a crash halfway through the header of the last delta
+        let length = commit(&mut state, &mut log, &[("key4", true)]);
+        let offset = file.read().metadata().unwrap().len() - length;
+        file.write().set_len(offset + 5).unwrap();
+        let (recovered, recovered_log, torn) = builder.build_with_log().unwrap();
+        assert_eq!(recovered.version, state.version - 1);
+        assert_eq!(recovered_log.deltas, CHECKPOINT_INTERVAL);
+        let mut torn = torn.unwrap();
+        assert_eq!(
+            (torn.offset, torn.kind),
+            (offset, CorruptionKind::Truncated)
+        );
+        torn.truncate(file.write().deref_mut()).unwrap();
+        assert!(torn.truncated);
+        assert_eq!(file.read().metadata().unwrap().len(), offset);
+        assert!(builder.build_with_log().unwrap().2.is_none());
+    }
+
+    #[test]
+    fn test_corruption() {
+        let mut file = tempfile().unwrap();
+        let mut data_writer = DataWriter {
+            file: &mut file,
+            data: vec![7_u8; 2000].into(),
+        };
+        let index = data_writer.write().unwrap();
+        let mut state = StateBuilder::versioned(1, BTreeMap::new());
+        state.indexes.insert("key".into(), index.clone());
+        let mut log = IndexLog::default();
+        let mut writer = StateWriter {
+            file: &mut file,
+            state: &state,
+            log: &mut log,
+        };
+        writer.write().unwrap();
+        // an empty delta after the checkpoint, so that the checkpoint is not the tail
+        state.version = 2;
+        log.changes.insert(2, vec![]);
+        let mut writer = StateWriter {
+            file: &mut file,
+            state: &state,
+            log: &mut log,
+        };
+        writer.write().unwrap();
+        let corrupt = |file: &mut File, position: u64, byte: u8| {
+            file.seek(SeekFrom::Start(position)).unwrap();
+            file.write_all(&[byte]).unwrap();
+        };
+        let corruption = |err: Error| match err {
+            Error::Corruption { file, offset, kind } => (file, offset, kind),
+            err => panic!("{}", err),
+        };
+
+        // a flipped bit in the value
+        corrupt(&mut file, index.offset + 1500, 8);
+        let mut retriever = DataRetriever {
+            file: &mut file,
+            offset: index.offset,
+            length: index.length,
+            checksum: index.checksum,
+        };
+        let err = corruption(retriever.retrieve().unwrap_err());
+        assert_eq!(err, ("".into(), index.offset, CorruptionKind::Checksum));
+        // a data page overwritten by something else
+        corrupt(retriever.file, PAGE_LEN, 9);
+        let err = corruption(retriever.retrieve().unwrap_err());
+        assert_eq!(err, ("".into(), PAGE_LEN, CorruptionKind::PageType(9)));
+
+        // a flipped bit in the index record, named by the caller
+        corrupt(&mut file, 2 * PAGE_LEN + 14, 0xff);
+        let builder = StateBuilder {
+            file: Arc::new(RwLock::new(file)),
+        };
+        let err = corruption(builder.build().err().unwrap().in_file("t.tree"));
+        assert_eq!(
+            err,
+            ("t.tree".into(), 2 * PAGE_LEN, CorruptionKind::Checksum)
+        );
+    }
 }
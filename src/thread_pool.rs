@@ -5,7 +5,7 @@ use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::thread;
 use std::thread::JoinHandle;
 
-pub type Worker = Box<dyn FnOnce() -> () + Send + 'static>;
+pub type Worker = Box<dyn FnOnce() + Send + 'static>;
 
 pub struct ThreadPool {
     pub sender: Option<Sender<Worker>>,
@@ -21,7 +21,7 @@ impl ThreadPool {
                 thread::spawn(move || {
                     while let Ok(worker) = worker_rx_cloned.recv() {
                         // let unwind_safe_worker = AssertUnwindSafe(worker);
-                        let _ = catch_unwind(AssertUnwindSafe(|| worker()));
+                        let _ = catch_unwind(AssertUnwindSafe(worker));
                     }
                 })
             })
@@ -35,12 +35,12 @@ impl ThreadPool {
 
     fn run<'scope, F>(&self, f: F)
     where
-        F: FnOnce() -> () + Send + 'scope,
+        F: FnOnce() + Send + 'scope,
     {
         let f = unsafe {
             std::mem::transmute::<
-                Box<dyn FnOnce() -> () + Send + 'scope>,
-                Box<dyn FnOnce() -> () + Send + 'static>,
+                Box<dyn FnOnce() + Send + 'scope>,
+                Box<dyn FnOnce() + Send + 'static>,
             >(Box::new(f))
         };
         let _ = self.sender.as_ref().unwrap().send(f);
@@ -59,9 +59,9 @@ impl ThreadPool {
         value_rs.recv()
     }
 
-    fn spawn<F>(&self, f: F)
+    pub fn spawn<F>(&self, f: F)
     where
-        F: FnOnce() -> () + Send + 'static,
+        F: FnOnce() + Send + 'static,
     {
         let _ = self.sender.as_ref().unwrap().send(Box::new(f));
     }
@@ -77,7 +77,7 @@ impl ThreadPool {
 
     pub fn scoped<F>(&self, f: F)
     where
-        F: FnOnce(&Scoped) -> (),
+        F: FnOnce(&Scoped),
     {
         let wg = WaitGroup::new();
         let scoped = Scoped {
@@ -104,7 +104,7 @@ pub struct Scoped<'pool> {
 impl<'pool> Scoped<'pool> {
     pub fn spawn<'scoped, F>(&self, f: F)
     where
-        F: FnOnce() -> () + 'scoped + Send,
+        F: FnOnce() + 'scoped + Send,
     {
         let wg = self.wait_group.clone();
         self.pool.run(move || {
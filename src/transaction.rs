@@ -1,57 +1,302 @@
+use crate::codec::crc32;
+use crate::error::CorruptionKind;
+use crate::state::{read_header, read_record, Index, TornRecord};
+use crate::tree::TransactionOptions;
 use crate::utils::{First, Windows};
 use crate::{Error, Result};
-use crossbeam::channel::{unbounded, Sender};
-use gstuff::oneshot::oneshot;
-use spin::RwLock;
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use serde::{Deserialize, Serialize};
+use spin::{Mutex, RwLock};
 
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::ops::DerefMut;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::Waker;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 pub const PAGE_LEN: u64 = 1024;
 
+/// How far a commit makes sure its changes have gone before it returns.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Durability {
+    /// Like `Flush`, but only waits for the log record to be written, neither for the
+    /// transactions before it nor for the index records of the tree files, which follow once
+    /// the log has caught up. A crash may lose the transactions the commit depends on.
+    None,
+    /// Writes the log record and the tree files, leaving them to the operating system. The
+    /// transaction survives the process crashing, but not the machine.
+    #[default]
+    Flush,
+    /// Like `Flush`, and syncs the data of the transaction, its log record and the tree files
+    /// to disk, along with the directory entries of newly created files.
+    Fsync,
+}
+
 pub enum TransactionAction {
-    Commit(TransactionCommitHandle),
+    Commit(Box<TransactionCommitHandle>),
     Drop(usize),
 }
 
 pub struct TransactionBatchBuilder {
     pub file: Arc<RwLock<File>>,
+    pub group_commit: GroupCommit,
+}
+
+/// How the log thread gathers commits into batches that share a single fsync.
+#[derive(Clone, Debug)]
+pub struct GroupCommit {
+    /// The most records written in one batch.
+    pub max_batch: usize,
+    /// How long the thread waits for more records once the first one of a batch arrived.
+    /// Records already queued join the batch even without waiting.
+    pub window: Duration,
+    /// How long a transaction id may stay neither logged nor dropped before the thread drops it,
+    /// so that a leaked transaction cannot hold back every later commit. `None`, the default,
+    /// waits forever for ids whose transaction is still around.
+    pub id_timeout: Option<Duration>,
+}
+
+/// How often the log thread looks for abandoned ids while idle.
+const REAP_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        Self {
+            max_batch: 128,
+            window: Duration::ZERO,
+            id_timeout: None,
+        }
+    }
+}
+
+/// An id handed out by [`TransactionBatch::new_id`] that is neither logged nor dropped yet.
+pub struct Lease {
+    /// Whatever keeps the transaction of the id around, if known.
+    pub owner: Option<Weak<dyn Any + Send + Sync>>,
+    pub issued: Instant,
+}
+
+/// The outstanding ids of a [`TransactionBatch`].
+#[derive(Default)]
+pub struct Leases {
+    pub outstanding: Mutex<HashMap<usize, Lease>>,
+    /// Ids that timed out while their transactions were still around, until the transactions
+    /// commit, drop the id or are gone.
+    pub reaped: Mutex<HashMap<usize, Lease>>,
+}
+
+impl Lease {
+    fn is_gone(&self) -> bool {
+        self.owner
+            .as_ref()
+            .is_some_and(|owner| owner.strong_count() == 0)
+    }
+}
+
+impl Leases {
+    /// Ends the lease of `id`, failing with [`Error::Reaped`] if the log thread dropped it.
+    fn release(&self, id: usize) -> Result<()> {
+        if self.outstanding.lock().remove(&id).is_none() && self.reaped.lock().remove(&id).is_some()
+        {
+            return Err(Error::Reaped(id));
+        }
+        Ok(())
+    }
+
+    /// Ends the lease of an id its transaction dropped. Returns `false` if the log thread
+    /// dropped the id already.
+    fn abandon(&self, id: usize) -> bool {
+        self.outstanding.lock().remove(&id).is_some() || self.reaped.lock().remove(&id).is_none()
+    }
+
+    /// Takes the ids whose transaction is gone, or that are older than `timeout`. Only the
+    /// latter are remembered as reaped, for as long as their transactions are around.
+    fn reap(&self, timeout: Option<Duration>) -> Vec<usize> {
+        let mut outstanding = self.outstanding.lock();
+        let abandoned: Vec<usize> = outstanding
+            .iter()
+            .filter(|(_, lease)| {
+                lease.is_gone() || timeout.is_some_and(|timeout| lease.issued.elapsed() > timeout)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        let mut reaped = self.reaped.lock();
+        reaped.retain(|_, lease| !lease.is_gone());
+        for id in abandoned.iter() {
+            let lease = outstanding.remove(id).unwrap();
+            if !lease.is_gone() {
+                reaped.insert(*id, lease);
+            }
+        }
+        abandoned
+    }
+}
+
+/// Counters kept by the log thread.
+#[derive(Debug, Default)]
+pub struct CommitMetrics {
+    pub batches: AtomicU64,
+    pub records: AtomicU64,
+    pub largest_batch: AtomicU64,
+    pub fsyncs: AtomicU64,
+    /// Time from handing a record to the log thread to it being written, summed over records.
+    pub latency_micros: AtomicU64,
+    pub max_latency_micros: AtomicU64,
+    /// Ids dropped because their transaction was gone or took too long.
+    pub reaped: AtomicU64,
+}
+
+impl CommitMetrics {
+    fn batch(&self, records: usize, synced: bool) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.records.fetch_add(records as u64, Ordering::Relaxed);
+        self.largest_batch
+            .fetch_max(records as u64, Ordering::Relaxed);
+        if synced {
+            self.fsyncs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn committed(&self, latency: Duration) {
+        let micros = latency.as_micros() as u64;
+        self.latency_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_latency_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn mean_batch(&self) -> f64 {
+        let batches = self.batches.load(Ordering::Relaxed);
+        self.records.load(Ordering::Relaxed) as f64 / batches.max(1) as f64
+    }
+
+    pub fn mean_latency(&self) -> Duration {
+        let records = self.records.load(Ordering::Relaxed).max(1);
+        Duration::from_micros(self.latency_micros.load(Ordering::Relaxed) / records)
+    }
 }
 
 impl TransactionBatchBuilder {
     pub fn build(&mut self) -> Result<TransactionBatch> {
-        let transaction_id = self.recover()?;
+        let transaction_id = self.recover()?.transaction_id;
+        self.start(transaction_id)
+    }
+
+    /// Starts the log thread, handing out ids after `transaction_id`.
+    pub fn start(&mut self, transaction_id: usize) -> Result<TransactionBatch> {
         let (sender, rx) = unbounded();
         let file = self.file.clone();
+        let group_commit = self.group_commit.clone();
+        let metrics = Arc::new(CommitMetrics::default());
+        let thread_metrics = metrics.clone();
+        let leases = Arc::new(Leases::default());
+        let thread_leases = leases.clone();
         let handle = thread::spawn(move || -> Result<()> {
+            let metrics = thread_metrics;
+            let leases = thread_leases;
             let mut windows = Windows::start_with(transaction_id + 1);
             let mut pending_transactions = vec![];
-            while let Ok(action) = rx.recv() {
-                match action {
-                    TransactionAction::Commit(handle) => {
-                        windows.put(handle.data.transaction_id);
-                        pending_transactions.push(handle);
+            loop {
+                let mut actions = match rx.recv_timeout(REAP_INTERVAL) {
+                    Ok(action) => vec![action],
+                    Err(RecvTimeoutError::Timeout) => vec![],
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                // abandoned ids are dropped as if their transactions had rolled back
+                let reaped = leases.reap(group_commit.id_timeout);
+                metrics
+                    .reaped
+                    .fetch_add(reaped.len() as u64, Ordering::Relaxed);
+                if actions.is_empty() && reaped.is_empty() {
+                    continue;
+                }
+                for id in reaped {
+                    windows.put(id);
+                }
+                // whatever arrives within the window joins the batch
+                let deadline = Instant::now() + group_commit.window;
+                while actions.len() < group_commit.max_batch {
+                    match rx.recv_deadline(deadline) {
+                        Ok(action) => actions.push(action),
+                        Err(_) => break,
+                    }
+                }
+                // the values the records point to reach the disk first, each file synced once
+                let mut tree_files: Vec<(Arc<RwLock<File>>, Result<()>)> = vec![];
+                for action in actions.iter() {
+                    let TransactionAction::Commit(handle) = action else {
+                        continue;
+                    };
+                    if handle.durability != Durability::Fsync {
+                        continue;
+                    }
+                    for tree_file in handle.files.iter() {
+                        let synced = tree_files.iter().any(|(f, _)| Arc::ptr_eq(f, tree_file));
+                        if !synced {
+                            let result = tree_file.read().sync_data().map_err(Error::from);
+                            tree_files.push((tree_file.clone(), result));
+                        }
                     }
-                    TransactionAction::Drop(id) => {
-                        windows.put(id);
+                }
+                // records are written in the order they arrive rather than by id, so a committer
+                // never waits on transactions with smaller ids while holding its tree locks
+                let mut file = file.write();
+                let mut written = vec![];
+                let mut sync = false;
+                for action in actions {
+                    match action {
+                        TransactionAction::Commit(mut handle) => {
+                            let unsynced = tree_files.iter().find_map(|(tree_file, result)| {
+                                let err = result.as_ref().err()?;
+                                let ours = handle.files.iter().any(|f| Arc::ptr_eq(f, tree_file));
+                                (ours && handle.durability == Durability::Fsync).then_some(err)
+                            });
+                            let result = match unsynced {
+                                Some(err) => Err(duplicate(err)),
+                                None => TransactionWriter {
+                                    file: file.deref_mut(),
+                                    transaction_id: handle.data.transaction_id,
+                                    data: handle.data.data.take(),
+                                }
+                                .write(),
+                            };
+                            windows.put(handle.data.transaction_id);
+                            sync |= handle.durability == Durability::Fsync;
+                            written.push((handle, result));
+                        }
+                        TransactionAction::Drop(id) => {
+                            if leases.abandon(id) {
+                                windows.put(id);
+                            }
+                        }
                     }
                 }
+                let synced = if sync { file.sync_data() } else { Ok(()) };
+                drop(file);
+                if !written.is_empty() {
+                    metrics.batch(written.len(), sync);
+                }
+                for (handle, mut result) in written {
+                    if let (Ok(()), Err(err), Durability::Fsync) =
+                        (&result, &synced, handle.durability)
+                    {
+                        result = Err(io::Error::new(err.kind(), err.to_string()).into());
+                    }
+                    metrics.committed(handle.queued.elapsed());
+                    match &result {
+                        Ok(()) => pending_transactions.push(handle.completion),
+                        Err(err) => handle.completion.complete(Err(duplicate(err))),
+                    }
+                    let _ = handle.written.send(result);
+                }
                 if windows.completed() {
-                    let mut file = file.write();
-                    pending_transactions.sort_by_key(|x| x.data.transaction_id);
-                    for tran in pending_transactions.drain(..) {
-                        let mut writer = TransactionWriter {
-                            file: file.deref_mut(),
-                            transaction_id: tran.data.transaction_id,
-                            data: tran.data.data,
-                        };
-                        writer.write()?;
-                        tran.sender.send(());
+                    pending_transactions.sort_by_key(|completion| completion.transaction_id);
+                    for completion in pending_transactions.drain(..) {
+                        completion.complete(Ok(()));
                     }
                 }
             }
@@ -61,52 +306,222 @@ impl TransactionBatchBuilder {
             transaction_id: AtomicUsize::new(transaction_id + 1),
             sender: Some(sender),
             handle: Some(handle),
+            metrics,
+            leases,
         })
     }
 
-    pub fn recover(&mut self) -> Result<usize> {
+    /// Reads the log back: the largest transaction id, the writes of committed transactions
+    /// and the prepared transactions still in doubt.
+    pub fn recover(&mut self) -> Result<Recovery> {
+        let mut recovery = Recovery::default();
+        let mut finished = vec![];
+        let LogRecords { records, torn, .. } = self.records_from(0)?;
+        recovery.torn = torn;
+        for (_, record) in records {
+            let id = record.transaction_id;
+            recovery.transaction_id = recovery.transaction_id.max(id);
+            let Some(data) = record.data else {
+                continue;
+            };
+            let write_set: WriteSet = serde_json::from_slice(&data[..])?;
+            match write_set.phase {
+                None => recovery
+                    .writes
+                    .extend(write_set.trees.into_iter().map(|tree| (id, tree))),
+                Some(Phase::Prepared) => {
+                    recovery.in_doubt.insert(id, write_set);
+                }
+                Some(phase) => finished.push((id, phase)),
+            }
+        }
+        for (id, phase) in finished {
+            match phase {
+                Phase::Commit(prepared) => {
+                    if let Some(write_set) = recovery.in_doubt.remove(&prepared) {
+                        recovery
+                            .writes
+                            .extend(write_set.trees.into_iter().map(|tree| (id, tree)));
+                    }
+                }
+                Phase::Rollback(prepared) => {
+                    recovery.in_doubt.remove(&prepared);
+                }
+                Phase::Prepared => {}
+            }
+        }
+        Ok(recovery)
+    }
+
+    /// Reads the records of the log from `start` on, in the order they were appended.
+    pub fn records_from(&mut self, start: u64) -> Result<LogRecords> {
         let mut file = self.file.write();
         let len = file.metadata()?.len();
-        let mut len = ((len + PAGE_LEN - 1) / PAGE_LEN) * PAGE_LEN;
-        if len == 0 {
-            Ok(0)
-        } else {
-            let mut buf = [0_u8; 1];
-            // find data header
-            while let Some(position) = len.checked_sub(PAGE_LEN as u64) {
-                len = position;
-                file.seek(SeekFrom::Start(position))?;
-                file.read_exact(&mut buf[..])?;
-                if buf[0] == 1_u8 {
-                    break;
+        let mut records = vec![];
+        let mut position = start.div_ceil(PAGE_LEN) * PAGE_LEN;
+        let mut buf = [0_u8; 1];
+        while position < len {
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut buf[..])?;
+            if buf[0] == 0 {
+                position += PAGE_LEN;
+                continue;
+            }
+            match Self::read_record(file.deref_mut(), position, buf[0]) {
+                Ok(record) => records.push((position, record)),
+                // only a record cut short is taken for a torn one, a complete one is corrupt
+                Err(Error::Corruption {
+                    kind: kind @ CorruptionKind::Truncated,
+                    ..
+                }) if Self::is_last(&mut file, position)? => {
+                    let torn = TornRecord::new(position, len - position, kind);
+                    return Ok(LogRecords {
+                        records,
+                        torn: Some(torn),
+                        end: len,
+                    });
                 }
+                Err(err) => return Err(err),
             }
-            // data_len
-            let mut buf = [0_u8; 4];
-            file.read_exact(&mut buf[..])?;
-            let mut total = u32::from_be_bytes(buf) as u64;
-            // transaction_id
-            let mut buf = [0_u8; 8];
+            position = file.stream_position()?.div_ceil(PAGE_LEN) * PAGE_LEN;
+        }
+        Ok(LogRecords {
+            records,
+            torn: None,
+            end: len,
+        })
+    }
+
+    /// Reads the record starting at `position`.
+    pub fn record_at(&mut self, position: u64) -> Result<TransactionData> {
+        let mut file = self.file.write();
+        let mut buf = [0_u8; 1];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut buf[..])?;
+        Self::read_record(file.deref_mut(), position, buf[0])
+    }
+
+    /// Reads the record whose header byte `kind` was just read at `position`.
+    fn read_record(file: &mut File, position: u64, kind: u8) -> Result<TransactionData> {
+        let header_len = match kind {
+            RECORD => 17,
+            LEGACY_RECORD => 13,
+            kind => return Err(Error::corruption(position, CorruptionKind::PageType(kind))),
+        };
+        // data_len, transaction_id and, unless legacy, the checksum
+        let mut header = [0_u8; 16];
+        read_header(file, position, &mut header[..header_len - 1])?;
+        let total = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let transaction_id = usize::from_be_bytes(header[4..12].try_into().unwrap());
+        let checksum =
+            (kind == RECORD).then(|| u32::from_be_bytes(header[12..16].try_into().unwrap()));
+        let seed = crc32(0, &header[4..12]);
+        let bytes = read_record(
+            file,
+            position,
+            header_len as u64,
+            total,
+            seed,
+            checksum,
+            |reader| {
+                let mut bytes = Vec::with_capacity(total as usize);
+                reader.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            },
+        )?;
+        Ok(TransactionData {
+            transaction_id,
+            data: if bytes.is_empty() { None } else { Some(bytes) },
+        })
+    }
+
+    /// Whether no record starts after the one at `position`.
+    fn is_last(file: &mut File, position: u64) -> Result<bool> {
+        let len = file.metadata()?.len();
+        let mut buf = [0_u8; 1];
+        let mut position = position + PAGE_LEN;
+        while position < len {
+            file.seek(SeekFrom::Start(position))?;
             file.read_exact(&mut buf[..])?;
-            let transaction_id = usize::from_be_bytes(buf);
-            let mut bytes = vec![0_u8; total as usize];
-            let mut first = First::new(13, 1);
-            let mut offset = 0;
-            while total > 0 {
-                if !first.first() {
-                    let mut buf = [1_u8; 1];
-                    file.read_exact(&mut buf[..])?;
-                    assert_eq!(buf[0], 0);
-                }
-                let header_len = first.get();
-                let page_rest = PAGE_LEN - header_len;
-                let to_read = total.min(page_rest);
-                file.read_exact(&mut bytes[offset as usize..(offset + to_read) as usize])?;
-                total -= to_read;
-                offset += to_read;
+            if buf[0] != 0 {
+                return Ok(false);
             }
-            Ok(transaction_id)
+            position += PAGE_LEN;
         }
+        Ok(true)
+    }
+}
+
+/// Header byte of a log record without a checksum, as older versions wrote them. Still read,
+/// never written.
+const LEGACY_RECORD: u8 = 1;
+/// Header byte of a log record: a `u32` length, the `u64` transaction id and the `u32` CRC-32
+/// of the id and the data.
+const RECORD: u8 = 3;
+
+/// Records read from the log by [`TransactionBatchBuilder::records_from`].
+pub struct LogRecords {
+    /// The records along with where they start.
+    pub records: Vec<(u64, TransactionData)>,
+    /// The record a crash left half written at the end of the log, if any.
+    pub torn: Option<TornRecord>,
+    /// The length of the log when it was read.
+    pub end: u64,
+}
+
+/// Everything a transaction changed, as logged before any of it is published.
+#[derive(Serialize, Deserialize)]
+pub struct WriteSet {
+    pub trees: Vec<TreeWrites>,
+    /// Set on the records of two-phase commits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Phase>,
+    /// Set on the records of prepared transactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<TransactionOptions>,
+}
+
+/// Where a two-phase commit was when its record was logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Phase {
+    /// The writes of the record only count once a later record commits them.
+    Prepared,
+    /// Commits the writes of the prepared transaction with this id.
+    Commit(usize),
+    /// Throws away the writes of the prepared transaction with this id.
+    Rollback(usize),
+}
+
+/// The content of the log, with prepared transactions matched to the records that finished
+/// them.
+#[derive(Default)]
+pub struct Recovery {
+    /// The largest transaction id in the log.
+    pub transaction_id: usize,
+    /// The writes of every committed transaction, along with the id they became visible at.
+    pub writes: Vec<(usize, TreeWrites)>,
+    /// The writes of the prepared transactions that were neither committed nor rolled back.
+    pub in_doubt: BTreeMap<usize, WriteSet>,
+    /// The record a crash left half written at the end of the log, if any.
+    pub torn: Option<TornRecord>,
+}
+
+/// The changes to one tree, which take it to `version`.
+#[derive(Serialize, Deserialize)]
+pub struct TreeWrites {
+    pub name: String,
+    pub version: u64,
+    pub writes: Vec<(String, Option<Index>)>,
+}
+
+impl TreeWrites {
+    /// The keys the writes remove.
+    pub fn removed(&self) -> Vec<String> {
+        self.writes
+            .iter()
+            .filter(|(_, index)| index.is_none())
+            .map(|(key, _)| key.clone())
+            .collect()
     }
 }
 
@@ -117,7 +532,15 @@ pub struct TransactionData {
 
 pub struct TransactionCommitHandle {
     pub data: TransactionData,
-    pub sender: gstuff::oneshot::Sender<()>,
+    pub durability: Durability,
+    /// The tree files holding the values the record points to, synced before the record is
+    /// written with [`Durability::Fsync`].
+    pub files: Vec<Arc<RwLock<File>>>,
+    pub queued: Instant,
+    /// Told once the record is in the log.
+    pub written: Sender<Result<()>>,
+    /// Told once every transaction with a smaller id is resolved as well.
+    pub completion: CommitCompletion,
 }
 
 pub struct TransactionWriter<'a> {
@@ -129,24 +552,28 @@ pub struct TransactionWriter<'a> {
 impl<'a> TransactionWriter<'a> {
     pub fn write(&mut self) -> Result<()> {
         let len = self.file.metadata()?.len();
-        let len = ((len + PAGE_LEN - 1) / PAGE_LEN) * PAGE_LEN;
+        let len = len.div_ceil(PAGE_LEN) * PAGE_LEN;
         self.file.set_len(len)?;
         self.file.seek(SeekFrom::Start(len))?;
-        self.file.write(&[1_u8])?;
+        self.file.write_all(&[RECORD])?;
         let mut total = self.data.as_ref().map(|x| x.len()).unwrap_or(0);
-        self.file.write(&(total as u32).to_be_bytes()[..])?;
-        self.file.write(&self.transaction_id.to_be_bytes()[..])?;
+        self.file.write_all(&(total as u32).to_be_bytes()[..])?;
+        self.file
+            .write_all(&self.transaction_id.to_be_bytes()[..])?;
+        let checksum = crc32(0, &self.transaction_id.to_be_bytes()[..]);
+        let checksum = crc32(checksum, self.data.as_deref().unwrap_or_default());
+        self.file.write_all(&checksum.to_be_bytes()[..])?;
         if let Some(data) = &self.data {
             let mut offset = 0;
-            let mut first = First::new(13, 1);
+            let mut first = First::new(17, 1);
             while total > 0 {
                 if !first.first() {
-                    self.file.write(&[0_u8])?;
+                    self.file.write_all(&[0_u8])?;
                 }
                 let header_len = first.get();
                 let page_rest = PAGE_LEN - header_len;
                 let to_write = total.min(page_rest as usize);
-                self.file.write(&data[offset..offset + to_write])?;
+                self.file.write_all(&data[offset..offset + to_write])?;
                 offset += to_write;
                 total -= to_write;
             }
@@ -155,10 +582,122 @@ impl<'a> TransactionWriter<'a> {
     }
 }
 
+/// Work left for when a commit is done.
+pub type Callback = Box<dyn FnOnce() + Send>;
+
+/// The callbacks of a commit, shared by its [`PendingCommit`] and the log thread.
+#[derive(Default)]
+pub struct Callbacks {
+    /// Whether the commit succeeded, once it is done.
+    pub done: Option<bool>,
+    pub queued: Vec<Callback>,
+}
+
+/// The log thread's end of a [`PendingCommit`].
+pub struct CommitCompletion {
+    pub transaction_id: usize,
+    pub done: Sender<Result<()>>,
+    pub waker: Arc<Mutex<Option<Waker>>>,
+    pub callbacks: Arc<Mutex<Callbacks>>,
+}
+
+impl CommitCompletion {
+    /// Runs the callbacks if the commit succeeded, then tells the [`PendingCommit`]. The record
+    /// is in the log by then, so nothing a callback does changes the outcome.
+    pub fn complete(self, result: Result<()>) {
+        let queued = {
+            let mut callbacks = self.callbacks.lock();
+            callbacks.done = Some(result.is_ok());
+            std::mem::take(&mut callbacks.queued)
+        };
+        if result.is_ok() {
+            for callback in queued {
+                callback();
+            }
+        }
+        let _ = self.done.send(result);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// A record handed to the log thread. It is done once the record is written and every
+/// transaction with a smaller id is resolved as well, or once writing it failed.
+pub struct PendingCommit {
+    pub written: Receiver<Result<()>>,
+    pub done: Receiver<Result<()>>,
+    /// Woken when the commit is done.
+    pub waker: Arc<Mutex<Option<Waker>>>,
+    pub callbacks: Arc<Mutex<Callbacks>>,
+}
+
+impl PendingCommit {
+    /// Waits for the record to be written.
+    pub fn written(&self) -> Result<()> {
+        self.written
+            .recv()
+            .map_err(|err| Error::Unknown(err.to_string()))?
+    }
+
+    pub fn wait(&self) -> Result<()> {
+        self.done
+            .recv()
+            .map_err(|err| Error::Unknown(err.to_string()))?
+    }
+
+    pub fn is_done(&self) -> bool {
+        !self.done.is_empty()
+    }
+
+    /// Leaves `callback` to the log thread to run once the commit succeeded, or runs it right
+    /// away if it already has. Dropped if the commit fails.
+    pub fn on_done(&self, callback: Callback) {
+        let mut callbacks = self.callbacks.lock();
+        match callbacks.done {
+            None => callbacks.queued.push(callback),
+            Some(true) => {
+                drop(callbacks);
+                callback()
+            }
+            Some(false) => {}
+        }
+    }
+
+    /// Takes the outcome of the commit if it is done, and otherwise leaves `waker` to be woken
+    /// when it is.
+    pub fn poll_done(&self, waker: &Waker) -> Option<Result<()>> {
+        if let Some(result) = self.try_done() {
+            return Some(result);
+        }
+        *self.waker.lock() = Some(waker.clone());
+        // the log thread may have finished before seeing the waker
+        self.try_done()
+    }
+
+    fn try_done(&self) -> Option<Result<()>> {
+        match self.done.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(err) => Some(Err(Error::Unknown(err.to_string()))),
+        }
+    }
+}
+
+/// A copy of `err` for a second receiver.
+fn duplicate(err: &Error) -> Error {
+    match err {
+        Error::IO(err) => io::Error::new(err.kind(), err.to_string()).into(),
+        err => Error::Unknown(err.to_string()),
+    }
+}
+
 pub struct TransactionBatch {
     pub sender: Option<Sender<TransactionAction>>,
     pub handle: Option<JoinHandle<Result<()>>>,
     pub transaction_id: AtomicUsize,
+    pub metrics: Arc<CommitMetrics>,
+    pub leases: Arc<Leases>,
 }
 
 impl Drop for TransactionBatch {
@@ -172,11 +711,56 @@ impl Drop for TransactionBatch {
 
 impl TransactionBatch {
     pub fn commit(&self, data: TransactionData) -> Result<()> {
-        let (sender, rx) = oneshot();
-        let action = TransactionAction::Commit(TransactionCommitHandle { data, sender });
+        self.append(data, Durability::Flush, vec![])?.wait()
+    }
+
+    /// Appends the record to the log and returns once it is written, leaving the wait for the
+    /// transactions before it to the returned handle.
+    pub fn append(
+        &self,
+        data: TransactionData,
+        durability: Durability,
+        files: Vec<Arc<RwLock<File>>>,
+    ) -> Result<PendingCommit> {
+        let pending = self.send(data, durability, files)?;
+        pending.written()?;
+        Ok(pending)
+    }
+
+    /// Hands the record to the log thread without waiting for anything. With
+    /// [`Durability::Fsync`], the log thread syncs `files` before writing the record. Fails with
+    /// [`Error::Reaped`] if the log thread has dropped the id of the record already.
+    pub fn send(
+        &self,
+        data: TransactionData,
+        durability: Durability,
+        files: Vec<Arc<RwLock<File>>>,
+    ) -> Result<PendingCommit> {
+        self.leases.release(data.transaction_id)?;
+        let (written, written_rx) = bounded(1);
+        let (done, done_rx) = bounded(1);
+        let waker = Arc::new(Mutex::new(None));
+        let callbacks = Arc::new(Mutex::new(Callbacks::default()));
+        let action = TransactionAction::Commit(Box::new(TransactionCommitHandle {
+            completion: CommitCompletion {
+                transaction_id: data.transaction_id,
+                done,
+                waker: waker.clone(),
+                callbacks: callbacks.clone(),
+            },
+            data,
+            durability,
+            files,
+            queued: Instant::now(),
+            written,
+        }));
         self.sender.as_ref().unwrap().send(action)?;
-        rx.recv().map_err(|err| Error::Unknown(err))?;
-        Ok(())
+        Ok(PendingCommit {
+            written: written_rx,
+            done: done_rx,
+            waker,
+            callbacks,
+        })
     }
 
     pub fn drop(&self, id: usize) -> Result<()> {
@@ -185,40 +769,86 @@ impl TransactionBatch {
         Ok(())
     }
 
+    /// Hands out the next id, leased until it is logged or dropped. Without an owner, only
+    /// [`GroupCommit::id_timeout`] takes it back.
     pub fn new_id(&self) -> usize {
-        self.transaction_id.fetch_add(1, Ordering::SeqCst)
+        self.lease(None)
+    }
+
+    /// Like [`TransactionBatch::new_id`], taking the id back as soon as `owner` is gone.
+    pub fn new_id_for(&self, owner: Weak<dyn Any + Send + Sync>) -> usize {
+        self.lease(Some(owner))
+    }
+
+    fn lease(&self, owner: Option<Weak<dyn Any + Send + Sync>>) -> usize {
+        let id = self.transaction_id.fetch_add(1, Ordering::SeqCst);
+        let lease = Lease {
+            owner,
+            issued: Instant::now(),
+        };
+        self.leases.outstanding.lock().insert(id, lease);
+        id
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::transaction::{TransactionBatchBuilder, TransactionData, TransactionWriter};
+    use crate::error::CorruptionKind;
+    use crate::transaction::{
+        Durability, GroupCommit, TransactionBatchBuilder, TransactionData, TransactionWriter,
+        TreeWrites, WriteSet, PAGE_LEN,
+    };
+    use crate::Error;
     use crossbeam::sync::WaitGroup;
     use spin::RwLock;
+    use std::any::Any;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::atomic::Ordering;
     use std::sync::Arc;
     use std::thread;
+    use std::time::Duration;
     use tempfile::tempfile;
 
     #[test]
     fn test_transaction_writer() {
         let mut file = tempfile().unwrap();
+        let write_set = WriteSet {
+            trees: vec![TreeWrites {
+                name: "t".repeat(1050),
+                version: 1,
+                writes: vec![],
+            }],
+            phase: None,
+            options: None,
+        };
         let mut writer = TransactionWriter {
             file: &mut file,
             transaction_id: 100,
-            data: Some(vec![1_u8; 1050]),
+            data: Some(serde_json::to_vec(&write_set).unwrap()),
         };
         writer.write().unwrap();
         let file = Arc::new(RwLock::new(file));
-        let mut builder = TransactionBatchBuilder { file };
-        let id = builder.recover().unwrap();
-        assert_eq!(id, 100);
+        let mut builder = TransactionBatchBuilder {
+            file,
+            group_commit: Default::default(),
+        };
+        let recovery = builder.recover().unwrap();
+        assert_eq!(recovery.transaction_id, 100);
+        assert_eq!(recovery.writes[0].1.name.len(), 1050);
     }
 
     #[test]
     fn test_transaction_batch() {
         let file = tempfile().unwrap();
         let file = Arc::new(RwLock::new(file));
-        let mut builder = TransactionBatchBuilder { file };
+        let mut builder = TransactionBatchBuilder {
+            file,
+            group_commit: GroupCommit {
+                max_batch: 16,
+                window: Duration::from_millis(5),
+                ..Default::default()
+            },
+        };
 
         let batch = Arc::new(builder.build().unwrap());
         let wg = WaitGroup::new();
@@ -226,17 +856,184 @@ mod test {
             let wg_clone = wg.clone();
             let batch_cloned = batch.clone();
             thread::spawn(move || {
+                let data = TransactionData {
+                    transaction_id: i,
+                    data: None,
+                };
+                let durability = if i % 2 == 0 {
+                    Durability::Fsync
+                } else {
+                    Durability::Flush
+                };
                 batch_cloned
-                    .commit(TransactionData {
-                        transaction_id: i,
-                        data: None,
-                    })
+                    .append(data, durability, vec![])
+                    .unwrap()
+                    .wait()
                     .unwrap();
                 drop(wg_clone);
             });
         }
         wg.wait();
-        let id = builder.recover().unwrap();
+        let id = builder.recover().unwrap().transaction_id;
         assert_eq!(id, 100);
+        let metrics = &batch.metrics;
+        assert_eq!(metrics.records.load(Ordering::Relaxed), 100);
+        let batches = metrics.batches.load(Ordering::Relaxed);
+        assert!(batches >= 7);
+        assert!(metrics.largest_batch.load(Ordering::Relaxed) <= 16);
+        assert!(metrics.fsyncs.load(Ordering::Relaxed) <= batches);
+        assert!(metrics.mean_batch() >= 1.0);
+    }
+
+    #[test]
+    fn test_reap_ids() {
+        let file = Arc::new(RwLock::new(tempfile().unwrap()));
+        let mut builder = TransactionBatchBuilder {
+            file,
+            group_commit: GroupCommit {
+                id_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        };
+        let batch = builder.build().unwrap();
+        let owner: Arc<dyn Any + Send + Sync> = Arc::new(());
+        batch.new_id_for(Arc::downgrade(&owner));
+        drop(owner);
+        let slow = batch.new_id();
+        let transaction_id = batch.new_id();
+        // waits for the two ids before it to be reaped
+        batch
+            .commit(TransactionData {
+                transaction_id,
+                data: None,
+            })
+            .unwrap();
+        assert_eq!(batch.metrics.reaped.load(Ordering::Relaxed), 2);
+        // only the id whose transaction may still come back is remembered
+        assert_eq!(batch.leases.reaped.lock().len(), 1);
+        let late = batch.commit(TransactionData {
+            transaction_id: slow,
+            data: None,
+        });
+        assert!(matches!(late, Err(Error::Reaped(id)) if id == slow));
+        assert!(batch.leases.reaped.lock().is_empty());
+
+        // and forgotten once that transaction is gone
+        let owner: Arc<dyn Any + Send + Sync> = Arc::new(());
+        batch.new_id_for(Arc::downgrade(&owner));
+        let commit = |transaction_id| {
+            batch
+                .commit(TransactionData {
+                    transaction_id,
+                    data: None,
+                })
+                .unwrap()
+        };
+        commit(batch.new_id());
+        assert_eq!(batch.leases.reaped.lock().len(), 1);
+        drop(owner);
+        commit(batch.new_id());
+        assert!(batch.leases.reaped.lock().is_empty());
+    }
+
+    #[test]
+    fn test_drop_only_batches() {
+        let file = Arc::new(RwLock::new(tempfile().unwrap()));
+        let mut builder = TransactionBatchBuilder {
+            file,
+            group_commit: GroupCommit::default(),
+        };
+        let batch = builder.build().unwrap();
+        let dropped = batch.new_id();
+        batch.drop(dropped).unwrap();
+        let tree_file = Arc::new(RwLock::new(tempfile().unwrap()));
+        let data = TransactionData {
+            transaction_id: batch.new_id(),
+            data: None,
+        };
+        // the tree file is synced by the log thread, along with the log
+        let files = vec![tree_file.clone(), tree_file];
+        let pending = batch.append(data, Durability::Fsync, files).unwrap();
+        pending.wait().unwrap();
+        let metrics = &batch.metrics;
+        assert_eq!(metrics.batches.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.records.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.fsyncs.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_log_checksums() {
+        let mut file = tempfile().unwrap();
+        let data = |name: &str| {
+            let write_set = WriteSet {
+                trees: vec![TreeWrites {
+                    name: name.to_owned(),
+                    version: 1,
+                    writes: vec![],
+                }],
+                phase: None,
+                options: None,
+            };
+            serde_json::to_vec(&write_set).unwrap()
+        };
+        // a record written before records had checksums
+        let legacy = data("legacy");
+        file.write_all(&[1_u8]).unwrap();
+        file.write_all(&(legacy.len() as u32).to_be_bytes()[..])
+            .unwrap();
+        file.write_all(&5_usize.to_be_bytes()[..]).unwrap();
+        file.write_all(&legacy[..]).unwrap();
+        let mut writer = TransactionWriter {
+            file: &mut file,
+            transaction_id: 6,
+            data: Some(data("checked")),
+        };
+        writer.write().unwrap();
+        let file = Arc::new(RwLock::new(file));
+        let mut builder = TransactionBatchBuilder {
+            file: file.clone(),
+            group_commit: Default::default(),
+        };
+        let recovery = builder.recover().unwrap();
+        assert_eq!(recovery.transaction_id, 6);
+        assert_eq!(recovery.writes.len(), 2);
+
+        file.write().seek(SeekFrom::Start(PAGE_LEN + 30)).unwrap();
+        file.write().write_all(b"X").unwrap();
+        // a complete record failing its checksum is an error, even at the end
+        let err = builder.recover().err().unwrap();
+        assert!(matches!(
+            err,
+            Error::Corruption {
+                offset: PAGE_LEN,
+                kind: CorruptionKind::Checksum,
+                ..
+            }
+        ));
+        // the last record falls back as torn when cut short, one before it is an error
+        file.write().set_len(PAGE_LEN + 30).unwrap();
+        let recovery = builder.recover().unwrap();
+        assert_eq!(recovery.writes.len(), 1);
+        let torn = recovery.torn.unwrap();
+        assert_eq!(
+            (torn.offset, torn.kind),
+            (PAGE_LEN, CorruptionKind::Truncated)
+        );
+        let mut guard = file.write();
+        let mut writer = TransactionWriter {
+            file: &mut guard,
+            transaction_id: 7,
+            data: Some(data("after")),
+        };
+        writer.write().unwrap();
+        drop(guard);
+        let err = builder.recover().err().unwrap();
+        assert!(matches!(
+            err,
+            Error::Corruption {
+                offset: PAGE_LEN,
+                ..
+            }
+        ));
     }
 }
@@ -1,13 +1,31 @@
-use crate::db::{Db, FileManager};
-use crate::lock::Lock;
-use crate::state::{DataRetriever, DataWriter, Index, State, StateWriter};
-use crate::transaction::TransactionData;
-use crate::Result;
+use crate::db::{Db, TRANSACTION_FILE};
+use crate::hook::PostCommitHook;
+use crate::ivec::IVec;
+use crate::lock::{KeyLocks, KeyRange, Lock, LockMode};
+use crate::monitor::ActiveTransaction;
+use crate::secondary::SecondaryIndex;
+use crate::state::{
+    BatchRetriever, DataWriter, Index, PublicState, ReadSet, State, StateWriter, StreamWriter,
+    ValueReader, VersionedState,
+};
+use crate::thread_pool::ThreadPool;
+use crate::transaction::{Durability, PendingCommit, Phase, TransactionData, WriteSet};
+use crate::{Error, Result};
 
+use serde::{Deserialize, Serialize};
+use spin::{Mutex, RwLock};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom};
 use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+use std::pin::Pin;
 
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 pub struct Tree {
     pub state: State,
@@ -24,65 +42,956 @@ impl Tree {
     }
 }
 
+/// How a transaction keeps other transactions out of its way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransactionMode {
+    /// Lock every tree of the transaction when it starts.
+    #[default]
+    Pessimistic,
+    /// Take no lock while running, record what is read, and fail the commit with
+    /// [`Error::Conflict`] if any of it was changed by a transaction that committed in between.
+    Optimistic,
+}
+
+/// What a pessimistic transaction locks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockGranularity {
+    /// Every tree of the transaction, for as long as it runs.
+    #[default]
+    Tree,
+    /// The keys and key ranges the transaction reads, shared, and the keys it writes,
+    /// exclusively. Transactions touching different keys of a tree run side by side and only
+    /// lock the trees while committing.
+    Key,
+}
+
+/// Logged with the record of a prepared transaction, so that it keeps them across restarts.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionOptions {
+    pub mode: TransactionMode,
+    /// Ignored by optimistic transactions.
+    pub granularity: LockGranularity,
+    /// How many times [`Db::transaction`] runs the transaction again after a conflict.
+    pub retries: usize,
+    /// How long to wait for each lock before failing with [`Error::LockTimeout`]. `None`, the
+    /// default, waits for as long as it takes; deadlocks are detected either way.
+    pub lock_timeout: Option<Duration>,
+    pub durability: Durability,
+    pub limits: TransactionLimits,
+}
+
+/// Caps on what a single transaction may write, enforced as it writes. Going over one fails the
+/// write with [`Error::LimitExceeded`], leaving the transaction as it was before it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionLimits {
+    /// Keys set or removed.
+    pub max_writes: Option<usize>,
+    /// Bytes of values written to the data files.
+    pub max_bytes: Option<u64>,
+    /// Estimated bytes the writes add to the writer states of the trees.
+    pub max_memory: Option<usize>,
+}
+
+/// What a transaction has written so far, see [`TransactionLimits`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransactionUsage {
+    pub writes: usize,
+    pub bytes: u64,
+    pub memory: usize,
+}
+
+impl TransactionUsage {
+    /// Roughly what writing `key` adds to a writer state: the key in both the written keys and
+    /// the indexes, along with their map entries.
+    fn entry_memory(key: &str) -> usize {
+        2 * (key.len() + std::mem::size_of::<String>()) + 2 * std::mem::size_of::<Option<Index>>()
+    }
+
+    fn check(&self, limits: &TransactionLimits) -> Result<()> {
+        let checks = [
+            (
+                "writes",
+                self.writes as u64,
+                limits.max_writes.map(|n| n as u64),
+            ),
+            ("bytes", self.bytes, limits.max_bytes),
+            (
+                "memory",
+                self.memory as u64,
+                limits.max_memory.map(|n| n as u64),
+            ),
+        ];
+        for (what, used, limit) in checks {
+            if let Some(limit) = limit.filter(|limit| used > *limit) {
+                return Err(Error::LimitExceeded(format!(
+                    "{} {} over the limit of {}",
+                    what, used, limit
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails once more than `remaining` bytes came through, so that a stream going over the byte
+/// limit stops before it is written out.
+struct LimitedReader<R> {
+    reader: R,
+    remaining: Option<u64>,
+    read: u64,
+}
+
+impl<R> LimitedReader<R> {
+    fn exceeded(&self) -> bool {
+        self.remaining
+            .is_some_and(|remaining| self.read > remaining)
+    }
+}
+
+impl<R> Read for LimitedReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.read += read as u64;
+        if self.exceeded() {
+            return Err(io::Error::other("stream over the byte limit"));
+        }
+        Ok(read)
+    }
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        Self {
+            mode: TransactionMode::default(),
+            granularity: LockGranularity::default(),
+            retries: 3,
+            lock_timeout: None,
+            durability: Durability::default(),
+            limits: TransactionLimits::default(),
+        }
+    }
+}
+
 pub struct TransactionTrees<'a> {
     pub trees: Vec<Tree>,
-    pub locks: Vec<Arc<Lock>>,
+    pub secondary: Vec<SecondaryBinding>,
+    pub options: TransactionOptions,
+    /// Identifies the transaction to the key locks of its trees.
+    pub owner: usize,
     pub committed: AtomicBool,
     pub db: &'a Db,
+    /// The locks and id of the transaction, shared with [`Db::active_transactions`].
+    pub active: Arc<ActiveTransaction>,
+    pub savepoints: Mutex<Savepoints>,
+    /// The id a transaction resumed by [`Db::commit_prepared`] or [`Db::rollback_prepared`] was
+    /// prepared under.
+    pub prepared: Option<usize>,
+    pub usage: Mutex<TransactionUsage>,
+    /// Held by nothing but the transaction, so that the log thread can tell when it is gone,
+    /// see [`TransactionBatch::new_id_for`](crate::transaction::TransactionBatch::new_id_for).
+    pub alive: Arc<()>,
+}
+
+/// What the database keeps of a transaction between [`TransactionTrees::prepare`] and its end.
+pub struct PreparedTransaction {
+    pub trees: Vec<Tree>,
+    /// The tree locks the transaction holds.
+    pub locks: Vec<Arc<Lock>>,
+    /// The owner of the key locks the transaction holds.
+    pub owner: usize,
+    pub options: TransactionOptions,
+    /// When the transaction started, or was found prepared in the log.
+    pub started: Instant,
+}
+
+/// What a commit did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommitInfo {
     pub transaction_id: usize,
+    pub durability: Durability,
+}
+
+/// Returned by [`TransactionTrees::commit_async`]. Can be polled, waited on or awaited. The
+/// post-commit hooks run when the handle learns the commit is done, or on
+/// [`Db::post_commit`] once it is if the handle is dropped before.
+pub struct CommitHandle {
+    pub info: CommitInfo,
+    pub pending: PendingCommit,
+    pub post_commit: Vec<PostCommitHook>,
+    pub runner: Arc<ThreadPool>,
+}
+
+impl CommitHandle {
+    /// Whether the commit is done, successfully or not.
+    pub fn is_done(&self) -> bool {
+        self.pending.is_done()
+    }
+
+    /// Blocks until the commit is done.
+    pub fn wait(mut self) -> Result<CommitInfo> {
+        self.pending.wait()?;
+        Ok(self.confirmed())
+    }
+
+    fn confirmed(&mut self) -> CommitInfo {
+        for hook in self.post_commit.drain(..) {
+            hook(self.info.transaction_id);
+        }
+        self.info
+    }
+}
+
+impl Drop for CommitHandle {
+    fn drop(&mut self) {
+        if self.post_commit.is_empty() {
+            return;
+        }
+        let hooks = std::mem::take(&mut self.post_commit);
+        let runner = self.runner.clone();
+        let transaction_id = self.info.transaction_id;
+        // dropped along with the hooks if the commit fails
+        self.pending.on_done(Box::new(move || {
+            runner.spawn(move || {
+                for hook in hooks {
+                    hook(transaction_id);
+                }
+            });
+        }));
+    }
+}
+
+impl Future for CommitHandle {
+    type Output = Result<CommitInfo>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.pending.poll_done(cx.waker()) {
+            Some(result) => Poll::Ready(result.map(|()| this.confirmed())),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Marks a point of a transaction that it can go back to with
+/// [`TransactionTrees::rollback_to`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+/// What the trees had written at each open savepoint, innermost last.
+#[derive(Default)]
+pub struct Savepoints {
+    pub next: usize,
+    pub stack: Vec<(Savepoint, Vec<TreeSavepoint>)>,
+}
+
+/// The writes of a writer state at a savepoint, along with the version it was at. The rest of
+/// the state is the published one and is not copied.
+pub struct TreeSavepoint {
+    pub writes: BTreeMap<String, Option<Index>>,
+    pub version: u64,
+}
+
+impl TreeSavepoint {
+    fn new(state: &VersionedState) -> Self {
+        Self {
+            writes: state.writes.clone(),
+            version: state.version,
+        }
+    }
+}
+
+/// Ties a secondary index to the positions of its primary tree and its index tree within a
+/// transaction.
+pub struct SecondaryBinding {
+    pub primary: usize,
+    pub tree: usize,
+    pub index: Arc<SecondaryIndex>,
 }
 
 impl<'a> TransactionTrees<'a> {
-    pub fn get(&self, idx: usize) -> IndexedTransactionTrees {
+    pub fn get(&self, idx: usize) -> IndexedTransactionTrees<'_, 'a> {
         assert!(idx < self.trees.len());
         IndexedTransactionTrees { trees: self, idx }
     }
 
-    pub fn commit(&self) -> Result<()> {
-        for tree in self.trees.iter() {
-            let file_name = FileManager::file_name(tree.name.as_str());
-            let file = self.db.file_manager.get_or_insert(file_name.as_str())?;
-            let mut file = file.write();
-            let mut state = tree.state.writer.lock();
-            if state.dirty {
+    pub fn tree(&self, name: &str) -> Option<IndexedTransactionTrees<'_, 'a>> {
+        self.position(name)
+            .map(|idx| IndexedTransactionTrees { trees: self, idx })
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.trees
+            .iter()
+            .position(|tree| tree.name.as_str() == name)
+    }
+
+    /// Adds the tree `name`, along with the index trees of its secondary indexes, to the running
+    /// transaction and returns its position. A transaction locking whole trees takes their locks
+    /// in name order, so it can only wait for the locks of trees that sort after those it has.
+    /// Any other tree is only added if its locks are free; otherwise this fails with
+    /// [`Error::LockOrder`] and the transaction has to start again with the tree.
+    pub fn add_tree<S: AsRef<str>>(&mut self, name: S) -> Result<usize> {
+        let name = name.as_ref();
+        if let Some(idx) = self.position(name) {
+            return Ok(idx);
+        }
+        let mut names = vec![name.to_owned()];
+        let indexes = self.db.indexes_of(names.iter());
+        for index in indexes.iter() {
+            let tree_name = index.tree_name();
+            if self.position(&tree_name).is_none() && !names.contains(&tree_name) {
+                names.push(tree_name);
+            }
+        }
+        let states = names
+            .iter()
+            .map(|name| self.db.public_state(name))
+            .collect::<Result<Vec<PublicState>>>()?;
+        let last = self.trees.iter().map(|tree| tree.name.to_string()).max();
+        let idx = self.trees.len();
+        for (name, state) in names.into_iter().zip(states) {
+            self.trees.push(Tree {
+                state: match self.options.mode {
+                    TransactionMode::Optimistic => State::pinned(state),
+                    TransactionMode::Pessimistic => State::new(state),
+                },
+                name: Arc::new(name),
+            });
+        }
+        if self.tree_locked() {
+            let first = self.trees[idx..]
+                .iter()
+                .map(|tree| tree.name.as_str())
+                .min();
+            let out_of_order = last.is_some_and(|last| first.unwrap() <= last.as_str());
+            if let Err(err) = self.lock_from(idx, out_of_order.then_some(name)) {
+                self.trees.truncate(idx);
+                return Err(err);
+            }
+        }
+        self.active.trees.lock().extend(
+            self.trees[idx..]
+                .iter()
+                .map(|tree| (tree.name.to_string(), tree.state.public.clone())),
+        );
+        for index in indexes {
+            self.secondary.push(SecondaryBinding {
+                primary: idx,
+                tree: self.position(&index.tree_name()).unwrap(),
+                index,
+            });
+        }
+        // the new trees had nothing written at any open savepoint
+        for (_, states) in self.savepoints.lock().stack.iter_mut() {
+            for tree in self.trees[states.len()..].iter() {
+                states.push(TreeSavepoint::new(&tree.state.writer.lock()));
+            }
+        }
+        Ok(idx)
+    }
+
+    /// Returns the primary entries whose value maps to `index_key` in the index `name`, as
+    /// `(key, value)` pairs ordered by primary key.
+    pub fn lookup_by_index<K>(&self, name: &str, index_key: K) -> Result<Vec<(IVec, IVec)>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let binding = self.binding(name)?;
+        let index_tree = self.get(binding.tree);
+        let primary = self.get(binding.primary);
+        index_tree
+            .keys(SecondaryIndex::entry_range(index_key.as_ref()))?
+            .into_iter()
+            .filter_map(|entry_key| {
+                let key = SecondaryIndex::primary_key(entry_key.as_bytes());
+                primary
+                    .get(key)
+                    .map(|value| value.map(|value| (IVec::from(key), value)))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    /// Clears the index tree of `name` and adds an entry for every entry of its primary tree.
+    pub fn rebuild_index(&self, name: &str) -> Result<()> {
+        let binding = self.binding(name)?;
+        let index_tree = self.get(binding.tree);
+        let primary = self.get(binding.primary);
+        for key in index_tree.keys::<&[u8], _>(..)? {
+            index_tree.remove_index(key.as_bytes())?;
+        }
+        for key in primary.keys::<&[u8], _>(..)? {
+            let key = key.as_bytes();
+            if let Some(value) = primary.get(key)? {
+                if let Some(index_key) = binding.index.extract(key, &value[..]) {
+                    index_tree.set(SecondaryIndex::entry_key(&index_key[..], key), &b""[..])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn binding(&self, name: &str) -> Result<&SecondaryBinding> {
+        self.secondary
+            .iter()
+            .find(|binding| binding.index.name == name)
+            .ok_or_else(|| Error::UnknownIndex(name.to_owned()))
+    }
+
+    /// Commits the transaction. The write set of every changed tree goes to the transaction log
+    /// first; only then is the new state published and the tree locks released. Once the log
+    /// has caught up with every earlier transaction, the changed index entries are appended to
+    /// the tree files; a tree whose index records fail to be appended refuses later writes with
+    /// [`Error::Poisoned`]. A crash at any point afterwards is repaired by replaying the log on
+    /// open. How much of this happens before `commit` returns depends on the [`Durability`] of
+    /// the transaction.
+    pub fn commit(&self) -> Result<CommitInfo> {
+        let durability = self.options.durability;
+        let mut handle = self.publish(true)?;
+        if durability == Durability::None {
+            // the handle leaves the post-commit hooks to run once the commit is done
+            return Ok(handle.info);
+        }
+        handle.pending.wait()?;
+        Ok(handle.confirmed())
+    }
+
+    /// Hands the write set to the transaction log, publishes it and releases the locks without
+    /// waiting for the log. The returned handle tells when the commit has the
+    /// [`Durability`] of the transaction. The index records follow once it is done, whether
+    /// or not the handle is still around.
+    pub fn commit_async(&self) -> Result<CommitHandle> {
+        self.publish(false)
+    }
+
+    /// Runs the pre-commit hooks, then logs and publishes the changed trees, waiting for the log
+    /// record to be written first if `written` is set. The index records of the published
+    /// states are appended once the commit is done.
+    fn publish(&self, written: bool) -> Result<CommitHandle> {
+        self.db.check_writable(TRANSACTION_FILE)?;
+        self.check_poisoned()?;
+        if !self.tree_locked() {
+            self.lock_trees()?;
+            self.validate()?;
+        }
+        let hooks = self.db.hooks.read().clone();
+        let mut post_commit = vec![];
+        if !hooks.pre_commit.is_empty() || !hooks.post_commit.is_empty() {
+            let pending = self.pending_writes(None);
+            // a prepared transaction went through the pre-commit hooks when it was prepared
+            if self.prepared.is_none() {
+                hooks.pre_commit(self, &pending)?;
+            }
+            post_commit = hooks.post_commit(&pending);
+        }
+        // an abort from here on would release the locks while the shared state is changed
+        self.active.start_commit()?;
+        let mut dirty: Vec<_> = self
+            .trees
+            .iter()
+            .map(|tree| (tree, tree.state.writer.lock()))
+            .filter(|(_, state)| state.dirty)
+            .collect();
+        let write_set = WriteSet {
+            trees: dirty
+                .iter_mut()
+                .map(|(tree, state)| {
+                    let writes = state.publish(tree.name.as_str());
+                    tree.state.public.prune(state, &writes);
+                    tree.state.public.index_log.lock().changed(&writes);
+                    writes
+                })
+                .collect(),
+            phase: None,
+            options: None,
+        };
+        let data = match self.prepared {
+            // the writes are in the log since the transaction was prepared
+            Some(prepared) => Some(serde_json::to_vec(&WriteSet {
+                trees: vec![],
+                phase: Some(Phase::Commit(prepared)),
+                options: None,
+            })?),
+            None if write_set.trees.is_empty() => None,
+            None => Some(serde_json::to_vec(&write_set)?),
+        };
+        let durability = self.options.durability;
+        if durability == Durability::Fsync {
+            self.db.file_manager.sync_dir()?;
+        }
+        let transaction_id = self.id();
+        let pending = self.db.batch.send(
+            TransactionData {
+                data,
+                transaction_id,
+            },
+            durability,
+            Self::files(dirty.iter().map(|(tree, _)| *tree)),
+        )?;
+        if written {
+            pending.written()?;
+        }
+        let guard = self.db.publish.write();
+        let published: Vec<_> = dirty
+            .iter_mut()
+            .map(|(tree, state)| {
+                // the transaction is done with its writer, which becomes the published state
+                let state = Arc::new(std::mem::take(state.deref_mut()));
+                *tree.state.public.reader.write() = state.clone();
+                (tree.state.public.clone(), state)
+            })
+            .collect();
+        drop(guard);
+        drop(dirty);
+        self.unlock();
+        self.committed.store(true, Ordering::SeqCst);
+        for (public, state) in published {
+            pending.on_done(Box::new(move || {
+                let mut file = public.file.write();
+                let mut log = public.index_log.lock();
                 let mut page_writer = StateWriter {
                     file: file.deref_mut(),
                     state: state.deref(),
+                    log: &mut log,
                 };
-                page_writer.write()?;
-                let mut reader = tree.state.public.reader.write();
-                std::mem::swap(reader.deref_mut(), state.deref_mut());
-                // *reader = state.clone()
-            }
+                // the commit is in the log either way, which opening the database replays
+                if let Err(err) = page_writer.write() {
+                    drop(file);
+                    public.poison(err);
+                }
+            }));
         }
-        for lock in self.locks.iter() {
-            lock.unlock();
+        let handle = CommitHandle {
+            info: CommitInfo {
+                transaction_id,
+                durability,
+            },
+            pending,
+            post_commit,
+            runner: self.db.post_commit.clone(),
+        };
+        Ok(handle)
+    }
+
+    /// The first phase of a two-phase commit. Logs the write set of the transaction without
+    /// publishing it and hands the transaction, along with its locks, over to the database,
+    /// where [`Db::commit_prepared`] or [`Db::rollback_prepared`] finishes it under the returned
+    /// id. Prepared transactions found in the log on open are taken up again, see
+    /// [`Db::in_doubt`]. The record is written at least with [`Durability::Flush`].
+    pub fn prepare(mut self) -> Result<usize> {
+        self.db.check_writable(TRANSACTION_FILE)?;
+        self.check_poisoned()?;
+        if !self.tree_locked() {
+            self.lock_trees()?;
+            self.validate()?;
+        }
+        let mut write_set = self.pending_writes(Some(Phase::Prepared));
+        write_set.options = Some(self.options.clone());
+        self.db.hooks.read().clone().pre_commit(&self, &write_set)?;
+        let durability = match self.options.durability {
+            Durability::None => Durability::Flush,
+            durability => durability,
+        };
+        if durability == Durability::Fsync {
+            self.db.file_manager.sync_dir()?;
         }
+        self.active.start_commit()?;
+        let transaction_id = self.id();
+        self.db
+            .batch
+            .append(
+                TransactionData {
+                    data: Some(serde_json::to_vec(&write_set)?),
+                    transaction_id,
+                },
+                durability,
+                Self::files(self.trees.iter()),
+            )?
+            .wait()?;
+        // the locks now belong to the prepared transaction
         self.committed.store(true, Ordering::SeqCst);
-        self.db.batch.commit(TransactionData {
-            data: None,
-            transaction_id: self.transaction_id,
-        })?;
+        let prepared = PreparedTransaction {
+            trees: std::mem::take(&mut self.trees),
+            locks: self.active.locks.lock().drain(..).collect(),
+            owner: self.owner,
+            options: self.options.clone(),
+            started: self.active.started,
+        };
+        self.db.prepared.lock().insert(transaction_id, prepared);
+        Ok(transaction_id)
+    }
+
+    /// The write set the transaction would log if it committed now.
+    fn pending_writes(&self, phase: Option<Phase>) -> WriteSet {
+        WriteSet {
+            trees: self
+                .trees
+                .iter()
+                .filter_map(|tree| {
+                    let state = tree.state.writer.lock();
+                    state.dirty.then(|| state.pending(tree.name.as_str()))
+                })
+                .collect(),
+            phase,
+            options: None,
+        }
+    }
+
+    /// The files of `trees`, which the log thread syncs before a record pointing to their
+    /// values.
+    fn files<'t>(trees: impl Iterator<Item = &'t Tree>) -> Vec<Arc<RwLock<File>>> {
+        trees.map(|tree| tree.state.public.file.clone()).collect()
+    }
+
+    pub fn savepoint(&self) -> Savepoint {
+        let states = self
+            .trees
+            .iter()
+            .map(|tree| TreeSavepoint::new(&tree.state.writer.lock()))
+            .collect();
+        let mut savepoints = self.savepoints.lock();
+        let savepoint = Savepoint(savepoints.next);
+        savepoints.next += 1;
+        savepoints.stack.push((savepoint, states));
+        savepoint
+    }
+
+    /// Undoes everything written since `savepoint` and closes the savepoints opened after it.
+    /// The savepoint itself stays open. Values written since stay in the data files, unreferenced.
+    pub fn rollback_to(&self, savepoint: Savepoint) -> Result<()> {
+        let mut savepoints = self.savepoints.lock();
+        let depth = savepoints
+            .stack
+            .iter()
+            .position(|(open, _)| *open == savepoint)
+            .ok_or(Error::UnknownSavepoint)?;
+        savepoints.stack.truncate(depth + 1);
+        for (tree, saved) in self.trees.iter().zip(savepoints.stack[depth].1.iter()) {
+            // keys first written since the savepoint go back to the entries the transaction
+            // started from; trees locked as a whole have not changed since
+            let base = match &tree.state.base {
+                Some(base) => base.clone(),
+                None => tree.state.public.snapshot(),
+            };
+            let mut writer = tree.state.writer.lock();
+            let writes = std::mem::replace(&mut writer.writes, saved.writes.clone());
+            for key in writes.into_keys() {
+                let index = match saved.writes.get(&key) {
+                    Some(index) => index.clone(),
+                    None => base.indexes.get(&key).cloned(),
+                };
+                match index {
+                    Some(index) => writer.indexes.insert(key, index),
+                    None => writer.indexes.remove(&key),
+                };
+            }
+            writer.version = saved.version;
+            writer.dirty = !writer.writes.is_empty();
+            if self.key_locked() {
+                // keys locked since the savepoint were brought up to date then
+                let range = (Bound::Unbounded, Bound::Unbounded);
+                writer.sync(&tree.state.public.snapshot(), &range);
+            }
+        }
+        // values written since stay in the data files and keep counting
+        let mut usage = self.usage.lock();
+        usage.writes = 0;
+        usage.memory = 0;
+        for tree in self.trees.iter() {
+            let writer = tree.state.writer.lock();
+            usage.writes += writer.writes.len();
+            usage.memory += writer
+                .writes
+                .keys()
+                .map(|key| TransactionUsage::entry_memory(key))
+                .sum::<usize>();
+        }
+        Ok(())
+    }
+
+    /// What the transaction has written so far.
+    pub fn usage(&self) -> TransactionUsage {
+        *self.usage.lock()
+    }
+
+    /// Fails with [`Error::Poisoned`] if a tree the transaction wrote to is poisoned.
+    fn check_poisoned(&self) -> Result<()> {
+        self.trees
+            .iter()
+            .filter(|tree| tree.state.writer.lock().dirty)
+            .try_for_each(|tree| tree.state.public.check_poisoned())
+    }
+
+    /// Bytes of value the transaction may still write, if it has a byte limit.
+    fn remaining_bytes(&self) -> Option<u64> {
+        let used = self.usage.lock().bytes;
+        self.options
+            .limits
+            .max_bytes
+            .map(|max| max.saturating_sub(used))
+    }
+
+    /// Accounts for writing `bytes` of value under `key` of the tree at `idx`, failing with
+    /// [`Error::LimitExceeded`] without accounting for anything if that goes over a limit.
+    fn charge(&self, idx: usize, key: &str, bytes: u64) -> Result<()> {
+        if self.active.is_aborted() {
+            return Err(Error::Aborted);
+        }
+        self.db.check_writable(TRANSACTION_FILE)?;
+        self.db
+            .check_writable(&self.trees[idx].state.public.file_name)?;
+        self.trees[idx].state.public.check_poisoned()?;
+        let mut usage = self.usage.lock();
+        let mut charged = *usage;
+        charged.bytes += bytes;
+        if !self.trees[idx].state.writer.lock().writes.contains_key(key) {
+            charged.writes += 1;
+            charged.memory += TransactionUsage::entry_memory(key);
+        }
+        charged.check(&self.options.limits)?;
+        *usage = charged;
+        Ok(())
+    }
+
+    /// Accounts for `bytes` more of value written under a key already charged for, failing
+    /// like [`TransactionTrees::charge`].
+    fn charge_bytes(&self, bytes: u64) -> Result<()> {
+        let mut usage = self.usage.lock();
+        let mut charged = *usage;
+        charged.bytes += bytes;
+        charged.check(&self.options.limits)?;
+        *usage = charged;
         Ok(())
     }
 
     pub fn rollback(&self) -> Result<()> {
+        if let Some(prepared) = self.prepared {
+            self.db.check_writable(TRANSACTION_FILE)?;
+            let write_set = WriteSet {
+                trees: vec![],
+                phase: Some(Phase::Rollback(prepared)),
+                options: None,
+            };
+            self.db
+                .batch
+                .append(
+                    TransactionData {
+                        data: Some(serde_json::to_vec(&write_set)?),
+                        transaction_id: self.id(),
+                    },
+                    Durability::Flush,
+                    vec![],
+                )?
+                .wait()?;
+            *self.active.transaction_id.lock() = None;
+        }
         self.committed.store(true, Ordering::SeqCst);
-        for lock in self.locks.iter() {
-            lock.unlock();
+        self.unlock();
+        if let Some(id) = self.active.transaction_id.lock().take() {
+            self.db.batch.drop(id)?;
+        }
+        Ok(())
+    }
+
+    pub fn transaction_id(&self) -> Option<usize> {
+        *self.active.transaction_id.lock()
+    }
+
+    pub(crate) fn id(&self) -> usize {
+        *self.active.transaction_id.lock().get_or_insert_with(|| {
+            let owner: Arc<dyn Any + Send + Sync> = self.alive.clone();
+            self.db.batch.new_id_for(Arc::downgrade(&owner))
+        })
+    }
+
+    pub(crate) fn tree_locked(&self) -> bool {
+        self.options.mode == TransactionMode::Pessimistic
+            && self.options.granularity == LockGranularity::Tree
+    }
+
+    fn key_locked(&self) -> bool {
+        self.options.mode == TransactionMode::Pessimistic
+            && self.options.granularity == LockGranularity::Key
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.options
+            .lock_timeout
+            .map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Locks the trees from `first` on for a transaction that locks whole trees, in name order,
+    /// and starts their writers from the state left by the previous holders of the locks. The
+    /// whole key range of each tree keeps out the transactions locking single keys. Trees joining
+    /// `out_of_order` are only locked if that needs no waiting, failing with
+    /// [`Error::LockOrder`] otherwise. On failure, none of the trees is left locked.
+    pub(crate) fn lock_from(&self, first: usize, out_of_order: Option<&str>) -> Result<()> {
+        let mut trees: Vec<&Tree> = self.trees[first..].iter().collect();
+        trees.sort_by_key(|tree| tree.name.clone());
+        let deadline = self.deadline();
+        let waits = &self.db.waits;
+        let busy = || Error::LockOrder(out_of_order.unwrap_or_default().to_owned());
+        let mut locked = vec![];
+        let mut result = Ok(());
+        for tree in trees.iter() {
+            let keys = &tree.state.public.keys;
+            let range = (Bound::Unbounded, Bound::Unbounded);
+            result = match out_of_order {
+                None => keys
+                    .lock(self.owner, range, LockMode::Exclusive, deadline)
+                    .map(|_| ()),
+                Some(_) if keys.try_lock(self.owner, range, LockMode::Exclusive) => Ok(()),
+                Some(_) => Err(busy()),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        for tree in trees.iter() {
+            if result.is_err() {
+                break;
+            }
+            let lock = &tree.state.public.lock;
+            result = match out_of_order {
+                None => self.wait_for(tree, || lock.lock_for(self.owner, waits, deadline)),
+                Some(_) if lock.try_lock_for(self.owner) => Ok(()),
+                Some(_) => Err(busy()),
+            };
+            if result.is_ok() {
+                locked.push(lock.clone());
+            }
+        }
+        if let Err(err) = result {
+            for tree in trees {
+                tree.state.public.keys.release(self.owner);
+            }
+            for lock in locked {
+                lock.unlock();
+            }
+            return Err(err);
+        }
+        self.hold(locked)?;
+        for tree in trees {
+            let snapshot = tree.state.public.snapshot();
+            let mut writer = tree.state.writer.lock();
+            // the writer was copied from the same state unless a commit came in between
+            if writer.version != snapshot.version {
+                *writer = VersionedState::clone(&snapshot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Locks the trees for a transaction that has run without holding them.
+    fn lock_trees(&self) -> Result<()> {
+        let mut trees: Vec<&Tree> = self.trees.iter().collect();
+        trees.sort_by_key(|tree| tree.name.clone());
+        let deadline = self.deadline();
+        let waits = &self.db.waits;
+        for tree in trees {
+            let lock = &tree.state.public.lock;
+            self.wait_for(tree, || lock.lock_for(self.owner, waits, deadline))?;
+            self.hold([lock.clone()])?;
+        }
+        self.id();
+        Ok(())
+    }
+
+    /// Hands `locks` to the transaction. An abort racing with taking them may have released
+    /// the locks of the transaction before they were added, so this releases them all and fails
+    /// with [`Error::Aborted`] if the transaction is aborted by now.
+    fn hold(&self, locks: impl IntoIterator<Item = Arc<Lock>>) -> Result<()> {
+        self.active.locks.lock().extend(locks);
+        if self.active.is_aborted() {
+            self.unlock();
+            return Err(Error::Aborted);
+        }
+        Ok(())
+    }
+
+    /// Runs `lock`, showing [`Db::active_transactions`] the transaction waits for `tree`.
+    fn wait_for<F: FnOnce() -> Result<()>>(&self, tree: &Tree, lock: F) -> Result<()> {
+        *self.active.waiting.lock() = Some(tree.name.to_string());
+        let result = lock();
+        *self.active.waiting.lock() = None;
+        result
+    }
+
+    /// Moves the writes of a transaction that did not hold its tree locks on top of the current
+    /// state of each tree. An optimistic transaction first checks that nothing it read has been
+    /// committed over since, and that no key it used is locked by another transaction.
+    fn validate(&self) -> Result<()> {
+        for tree in self.trees.iter() {
+            let current = tree.state.public.snapshot();
+            let mut writer = tree.state.writer.lock();
+            if self.options.mode == TransactionMode::Optimistic {
+                let reads = tree.state.reads.lock();
+                let conflict = reads
+                    .validate(writer.version, &current)
+                    .map(|key| format!("{} in tree {} changed since it was read", key, tree.name))
+                    .or_else(|| {
+                        self.locked_key(tree, &reads, &writer)
+                            .map(|key| format!("{} in tree {} is locked", key, tree.name))
+                    });
+                if let Some(conflict) = conflict {
+                    drop(reads);
+                    drop(writer);
+                    self.rollback()?;
+                    return Err(Error::Conflict(conflict));
+                }
+            }
+            if current.version != writer.version {
+                writer.rebase(&current);
+            }
         }
-        self.db.batch.drop(self.transaction_id)?;
         Ok(())
     }
+
+    /// A key read or written by an optimistic transaction that another transaction holds a key
+    /// lock on.
+    fn locked_key(&self, tree: &Tree, reads: &ReadSet, writer: &VersionedState) -> Option<String> {
+        let keys = &tree.state.public.keys;
+        let shared = reads
+            .keys
+            .keys()
+            .map(|key| KeyLocks::key(key))
+            .chain(reads.ranges.iter().cloned())
+            .map(|range| (range, LockMode::Shared));
+        let exclusive = writer
+            .writes
+            .keys()
+            .map(|key| (KeyLocks::key(key), LockMode::Exclusive));
+        shared
+            .chain(exclusive)
+            .find(|(range, mode)| !keys.is_free(self.owner, range, *mode))
+            .map(|(range, _)| match range.0 {
+                Bound::Included(key) | Bound::Excluded(key) => key,
+                Bound::Unbounded => String::new(),
+            })
+    }
+
+    fn unlock(&self) {
+        for tree in self.trees.iter() {
+            tree.state.public.keys.release(self.owner);
+        }
+        for lock in self.active.locks.lock().drain(..) {
+            lock.unlock();
+        }
+    }
 }
 
 impl<'a> Drop for TransactionTrees<'a> {
     fn drop(&mut self) {
+        self.db.active.lock().remove(&self.owner);
+        self.db.waits.forget(self.owner);
         if !self.committed.load(Ordering::SeqCst) {
-            for lock in &self.locks {
-                lock.unlock()
+            self.unlock();
+            if let Some(id) = self.active.transaction_id.lock().take() {
+                let _ = self.db.batch.drop(id);
             }
-            self.db.batch.drop(self.transaction_id);
         }
     }
 }
@@ -93,187 +1002,1111 @@ pub struct IndexedTransactionTrees<'a, 'db> {
 }
 
 impl<'a, 'db> IndexedTransactionTrees<'a, 'db> {
-    pub fn set<K>(&self, key: K, value: Vec<u8>) -> Result<()>
+    pub fn set<K, V>(&self, key: K, value: V) -> Result<()>
     where
         K: AsRef<[u8]>,
+        V: Into<IVec>,
     {
-        let key = key.as_ref();
-        let value = Arc::new(value);
+        let value = value.into();
+        self.lock_key(key.as_ref(), LockMode::Exclusive)?;
+        self.trees
+            .charge(self.idx, str_key(key.as_ref()), value.len() as u64)?;
+        let old = self.indexed_value(key.as_ref())?;
+        let file = self.file();
+        let mut file = file.write();
+        let mut data_writer = DataWriter {
+            file: file.deref_mut(),
+            data: value.clone(),
+        };
+        let index = data_writer.write()?;
+        drop(file);
         let tree = self.trees.trees.get(self.idx).unwrap();
-        let file_name = FileManager::file_name(tree.name.as_str());
-        let file = self
-            .trees
-            .db
-            .file_manager
-            .get_or_insert(file_name.as_str())?;
-        {
-            let mut file = file.write();
-            let mut data_writer = DataWriter {
-                file: file.deref_mut(),
-                data: value.clone(),
-            };
-            let offset = data_writer.write()?;
-            drop(file);
-            let mut guard = tree.state.writer.lock();
-            guard.indexes.insert(
-                unsafe { std::str::from_utf8_unchecked(key) }.to_owned(),
-                Index {
-                    offset,
-                    length: value.len() as u64,
-                },
-            );
-            guard.dirty = true;
-        }
+        tree.state
+            .public
+            .cache
+            .write()
+            .insert(index.offset as usize, value.clone());
+        self.insert_index(key.as_ref(), index);
+        self.update_secondary(key.as_ref(), old, Some(value))
+    }
 
+    /// Stores everything `reader` yields under `key` without buffering the whole value. The value
+    /// is spooled to a temporary file in the database directory first, so the tree file is not
+    /// locked while `reader` is slow to yield. Reading stops as soon as the value goes over the
+    /// byte limit.
+    ///
+    /// Memory stays bounded only on trees without secondary indexes. Index key extractors are
+    /// handed full values, so on an indexed tree both the value replaced and the one streamed
+    /// are read back into memory whole.
+    pub fn put_stream<K, R>(&self, key: K, reader: R) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        R: Read,
+    {
+        self.lock_key(key.as_ref(), LockMode::Exclusive)?;
+        self.trees.charge(self.idx, str_key(key.as_ref()), 0)?;
+        let old = self.indexed_value(key.as_ref())?;
+        let mut spool = tempfile::tempfile_in(&self.trees.db.file_manager.dir)?;
+        let mut reader = LimitedReader {
+            reader,
+            remaining: self.trees.remaining_bytes(),
+            read: 0,
+        };
+        let spooled = io::copy(&mut reader, &mut spool);
+        if reader.exceeded() {
+            // fails with the same error as going over the limit by any other write
+            return self.trees.charge_bytes(reader.read);
+        }
+        spooled?;
+        spool.seek(SeekFrom::Start(0))?;
+        let file = self.file();
+        let mut file = file.write();
+        let mut stream_writer = StreamWriter {
+            file: file.deref_mut(),
+            reader: &mut spool,
+        };
+        let written = stream_writer.write();
+        drop(file);
+        let index = written?;
+        // the write itself was charged for before reading the stream
+        self.trees.charge_bytes(index.length)?;
+        self.insert_index(key.as_ref(), index);
+        if self.has_secondary() {
+            // extractors take the whole value, see above
+            let value = self.get(key.as_ref())?;
+            self.update_secondary(key.as_ref(), old, value)?;
+        }
         Ok(())
     }
 
-    pub fn get<K>(&self, key: K) -> Result<Option<Vec<u8>>>
+    /// Opens the value stored under `key` for reading in chunks.
+    pub fn get_reader<K>(&self, key: K) -> Result<Option<ValueReader>>
     where
         K: AsRef<[u8]>,
     {
+        let index = self.index(key.as_ref())?;
+        let public = &self.trees.trees[self.idx].state.public;
+        let file_name = public.file_name.clone();
+        index
+            .map(|index| Ok(ValueReader::new(public.file.clone(), file_name, index)))
+            .transpose()
+    }
+
+    fn insert_index(&self, key: &[u8], index: Index) {
         let tree = self.trees.trees.get(self.idx).unwrap();
-        let key = key.as_ref();
-        let index = {
-            let guard = tree.state.writer.lock();
-            if let Some(value) = guard
-                .indexes
-                .get(unsafe { std::str::from_utf8_unchecked(key) })
-            {
-                Some(value.clone())
-            } else {
-                None
+        let mut guard = tree.state.writer.lock();
+        guard.insert(
+            unsafe { std::str::from_utf8_unchecked(key) }.to_owned(),
+            index,
+        );
+    }
+
+    fn remove_index(&self, key: &[u8]) -> Result<()> {
+        self.lock_key(key, LockMode::Exclusive)?;
+        self.trees.charge(self.idx, str_key(key), 0)?;
+        let tree = self.trees.trees.get(self.idx).unwrap();
+        let mut guard = tree.state.writer.lock();
+        guard.remove(unsafe { std::str::from_utf8_unchecked(key) });
+        Ok(())
+    }
+
+    fn has_secondary(&self) -> bool {
+        self.trees
+            .secondary
+            .iter()
+            .any(|binding| binding.primary == self.idx)
+    }
+
+    /// The current value of `key` if a secondary index will need it to drop a stale entry.
+    fn indexed_value(&self, key: &[u8]) -> Result<Option<IVec>> {
+        if self.has_secondary() {
+            self.get(key)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Moves the entries of `key` in every secondary index of this tree from the index key of
+    /// `old` to the index key of `new`.
+    fn update_secondary(&self, key: &[u8], old: Option<IVec>, new: Option<IVec>) -> Result<()> {
+        for binding in self.trees.secondary.iter() {
+            if binding.primary != self.idx {
+                continue;
             }
-        };
-        let value: Result<Option<Vec<u8>>> = index
-            .map(|idx| {
-                let mut cache = tree.state.public.cache.write();
-                if let Some(value) = cache.get(&(idx.offset as usize)) {
-                    Ok(value.clone())
-                } else {
-                    drop(cache);
-                    let file_name = FileManager::file_name(tree.name.as_str());
-                    let file = self
-                        .trees
-                        .db
-                        .file_manager
-                        .get_or_insert(file_name.as_str())?;
-                    let mut file = file.write();
-                    let mut retriever = DataRetriever {
-                        file: file.deref_mut(),
-                        offset: idx.offset,
-                        length: idx.length,
-                    };
-                    let value = retriever.retrieve()?;
-                    Ok(value)
-                }
-            })
-            .transpose();
-        Ok(value?)
+            let old_key = old
+                .as_ref()
+                .and_then(|value| binding.index.extract(key, &value[..]));
+            let new_key = new
+                .as_ref()
+                .and_then(|value| binding.index.extract(key, &value[..]));
+            if old_key == new_key {
+                continue;
+            }
+            let index_tree = self.trees.get(binding.tree);
+            if let Some(old_key) = old_key {
+                index_tree.remove_index(&SecondaryIndex::entry_key(&old_key[..], key)[..])?;
+            }
+            if let Some(new_key) = new_key {
+                index_tree.set(SecondaryIndex::entry_key(&new_key[..], key), &b""[..])?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn scan<K, R>(&self, keys: R) -> Result<Vec<Vec<u8>>>
+    /// Returns the keys within `keys`, in order.
+    pub fn keys<K, R>(&self, keys: R) -> Result<Vec<String>>
     where
         K: AsRef<[u8]>,
         R: RangeBounds<K>,
     {
         let tree = self.trees.trees.get(self.idx).unwrap();
+        let (lo, hi) = str_bounds(&keys);
+        self.read_range(lo, hi)?;
         let guard = tree.state.writer.lock();
+        Ok(guard
+            .indexes
+            .range::<str, _>((lo, hi))
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
 
-        let lo = match keys.start_bound() {
-            Bound::Included(k) => {
-                Bound::Included(unsafe { std::str::from_utf8_unchecked(k.as_ref()) })
-            }
-            Bound::Excluded(k) => {
-                Bound::Excluded(unsafe { std::str::from_utf8_unchecked(k.as_ref()) })
+    fn index(&self, key: &[u8]) -> Result<Option<Index>> {
+        let tree = self.trees.trees.get(self.idx).unwrap();
+        self.lock_key(key, LockMode::Shared)?;
+        let key = unsafe { std::str::from_utf8_unchecked(key) };
+        let guard = tree.state.writer.lock();
+        if self.trees.options.mode == TransactionMode::Optimistic {
+            let mut reads = tree.state.reads.lock();
+            if !reads.keys.contains_key(key) {
+                reads.keys.insert(key.to_owned(), guard.key_version(key));
             }
-            Bound::Unbounded => Bound::Unbounded,
-        };
+        }
+        Ok(guard.indexes.get(key).cloned())
+    }
 
-        let hi = match keys.end_bound() {
-            Bound::Included(k) => {
-                Bound::Included(unsafe { std::str::from_utf8_unchecked(k.as_ref()) })
-            }
-            Bound::Excluded(k) => {
-                Bound::Excluded(unsafe { std::str::from_utf8_unchecked(k.as_ref()) })
+    /// Locks or records a range the transaction is about to read.
+    fn read_range(&self, lo: Bound<&str>, hi: Bound<&str>) -> Result<()> {
+        let range = (lo.map(|x| x.to_owned()), hi.map(|x| x.to_owned()));
+        if self.trees.key_locked() {
+            self.lock_range(range, LockMode::Shared)?;
+        } else if self.trees.options.mode == TransactionMode::Optimistic {
+            let tree = self.trees.trees.get(self.idx).unwrap();
+            tree.state.reads.lock().ranges.push(range);
+        }
+        Ok(())
+    }
+
+    fn lock_key(&self, key: &[u8], mode: LockMode) -> Result<()> {
+        if !self.trees.key_locked() {
+            return Ok(());
+        }
+        let key = unsafe { std::str::from_utf8_unchecked(key) };
+        self.lock_range(KeyLocks::key(key), mode)
+    }
+
+    /// Takes a key lock. Commits made to the range before the lock was granted are brought into
+    /// the writer, nothing can change the range afterwards.
+    fn lock_range(&self, range: KeyRange, mode: LockMode) -> Result<()> {
+        let tree = self.trees.trees.get(self.idx).unwrap();
+        let public = &tree.state.public;
+        let deadline = self.trees.deadline();
+        if public
+            .keys
+            .lock(self.trees.owner, range.clone(), mode, deadline)?
+        {
+            let current = public.snapshot();
+            tree.state.writer.lock().sync(&current, &range);
+        }
+        Ok(())
+    }
+
+    fn file(&self) -> Arc<RwLock<File>> {
+        let tree = self.trees.trees.get(self.idx).unwrap();
+        tree.state.public.file.clone()
+    }
+
+    pub fn get<K>(&self, key: K) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let index = self.index(key.as_ref())?;
+        index.map(|index| self.value(&index)).transpose()
+    }
+
+    /// Fetches several keys at once. Cache hits never touch the file, and the remaining values
+    /// are read in file order under a single file lock, with neighbouring values coalesced into
+    /// one read. The results follow the order of `keys`.
+    pub fn get_many<I, K>(&self, keys: I) -> Result<Vec<Option<IVec>>>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        let tree = self.trees.trees.get(self.idx).unwrap();
+        let indexes = keys
+            .into_iter()
+            .map(|key| self.index(key.as_ref()))
+            .collect::<Result<Vec<Option<Index>>>>()?;
+        let mut values: Vec<Option<IVec>> = vec![None; indexes.len()];
+        let mut misses = vec![];
+        let mut miss_indexes = vec![];
+        {
+            let mut cache = tree.state.public.cache.write();
+            for (i, index) in indexes.iter().enumerate() {
+                if let Some(index) = index {
+                    match cache.get(&(index.offset as usize)) {
+                        Some(value) => values[i] = Some(value.clone()),
+                        None => {
+                            misses.push(i);
+                            miss_indexes.push(index.clone());
+                        }
+                    }
+                }
             }
-            Bound::Unbounded => Bound::Unbounded,
+        }
+        if misses.is_empty() {
+            return Ok(values);
+        }
+        let file = self.file();
+        let mut file = file.write();
+        let mut retriever = BatchRetriever {
+            file: file.deref_mut(),
+            indexes: &miss_indexes[..],
         };
-
-        let ranges = guard.indexes.range::<str, _>((lo, hi));
+        let retrieved = retriever
+            .retrieve()
+            .map_err(|err| err.in_file(&tree.state.public.file_name))?;
+        drop(file);
         let mut cache = tree.state.public.cache.write();
-        let values: Result<Vec<Vec<u8>>> = ranges
-            .map(|(_, idx)| {
-                if let Some(value) = cache.get(&(idx.offset as usize)) {
-                    Ok(value.clone())
-                } else {
-                    let file_name = FileManager::file_name(tree.name.as_str());
-                    let file = self
-                        .trees
-                        .db
-                        .file_manager
-                        .get_or_insert(file_name.as_str())?;
-                    let mut file = file.write();
-                    let mut retriever = DataRetriever {
-                        file: file.deref_mut(),
-                        offset: idx.offset,
-                        length: idx.length,
-                    };
-                    let value = retriever.retrieve()?;
-                    Ok(value)
-                }
-            })
-            .collect();
-        Ok(values?)
+        for ((i, index), value) in misses.into_iter().zip(miss_indexes).zip(retrieved) {
+            cache.insert(index.offset as usize, value.clone());
+            values[i] = Some(value);
+        }
+        Ok(values)
+    }
+
+    fn value(&self, index: &Index) -> Result<IVec> {
+        let tree = self.trees.trees.get(self.idx).unwrap();
+        tree.state.public.value(index)
     }
 
-    pub fn remove<K>(&self, key: K) -> Result<Option<Vec<u8>>>
+    pub fn scan<K, R>(&self, keys: R) -> Result<Vec<IVec>>
     where
         K: AsRef<[u8]>,
+        R: RangeBounds<K>,
     {
         let tree = self.trees.trees.get(self.idx).unwrap();
+        let (lo, hi) = str_bounds(&keys);
+        self.read_range(lo, hi)?;
+        let guard = tree.state.writer.lock();
+        let ranges = guard.indexes.range::<str, _>((lo, hi));
+        ranges.map(|(_, index)| self.value(index)).collect()
+    }
+
+    pub fn remove<K>(&self, key: K) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+    {
         let key = key.as_ref();
+        self.lock_key(key, LockMode::Exclusive)?;
         let value = self.get(key)?;
-        let mut guard = tree.state.writer.lock();
-        guard
-            .indexes
-            .remove(unsafe { std::str::from_utf8_unchecked(key) });
-        guard.dirty = true;
+        self.remove_index(key)?;
+        self.update_secondary(key, value.clone(), None)?;
         Ok(value)
     }
 }
 
+fn str_key(key: &[u8]) -> &str {
+    unsafe { std::str::from_utf8_unchecked(key) }
+}
+
+pub(crate) fn str_bounds<'k, K, R>(keys: &'k R) -> (Bound<&'k str>, Bound<&'k str>)
+where
+    K: AsRef<[u8]> + 'k,
+    R: RangeBounds<K>,
+{
+    let lo = match keys.start_bound() {
+        Bound::Included(k) => Bound::Included(unsafe { std::str::from_utf8_unchecked(k.as_ref()) }),
+        Bound::Excluded(k) => Bound::Excluded(unsafe { std::str::from_utf8_unchecked(k.as_ref()) }),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+
+    let hi = match keys.end_bound() {
+        Bound::Included(k) => Bound::Included(unsafe { std::str::from_utf8_unchecked(k.as_ref()) }),
+        Bound::Excluded(k) => Bound::Excluded(unsafe { std::str::from_utf8_unchecked(k.as_ref()) }),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (lo, hi)
+}
+
 #[cfg(test)]
 mod test {
     use crate::db::Db;
+    use crate::error::Error;
+    use crate::ivec::IVec;
+    use crate::secondary::SecondaryIndex;
+    use crate::transaction::{Durability, TransactionData, WriteSet, PAGE_LEN};
+    use crate::tree::{LockGranularity, TransactionLimits, TransactionMode, TransactionOptions};
+    use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError};
+    use std::future::Future;
+    use std::io::{Read, Seek, SeekFrom};
     use std::ops::Bound;
+    use std::pin::pin;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::tempdir;
 
     #[test]
     fn test_transaction() {
-        let db = Db::new().unwrap();
-        // db.open_tree("tree1").unwrap();
-        // db.open_tree("tree2").unwrap();
-        let trees = db
-            .start_transaction(["tree1", "tree2"].into_iter())
-            .unwrap();
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let trees = db.start_transaction(["tree1", "tree2"]).unwrap();
         let t1 = trees.get(0);
         let value1 = "value1".as_bytes().to_vec();
         t1.set("key1", value1.clone()).unwrap();
-        assert_eq!(t1.get("key1").unwrap(), Some(value1.clone()));
+        assert_eq!(t1.get("key1").unwrap(), Some(value1.clone().into()));
         let range = t1
             .scan::<&str, _>((Bound::Included("key0"), Bound::Excluded("key2")))
             .unwrap();
         assert_eq!(range.len(), 1);
         assert_eq!(range[0], value1.clone());
-        assert_eq!(t1.remove("key1").unwrap(), Some(value1.clone()));
+        assert_eq!(t1.remove("key1").unwrap(), Some(value1.clone().into()));
         assert_eq!(t1.get("key1").unwrap(), None);
         // commit
         t1.set("key1", value1.clone()).unwrap();
         trees.commit().unwrap();
-        let trees = db
-            .start_transaction(["tree1", "tree2"].into_iter())
-            .unwrap();
+        let trees = db.start_transaction(["tree1", "tree2"]).unwrap();
         let t1 = trees.get(0);
-        assert_eq!(t1.get("key1").unwrap(), Some(value1.clone()));
+        assert_eq!(t1.get("key1").unwrap(), Some(value1.clone().into()));
+    }
+
+    #[test]
+    fn test_stream() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let value: Vec<u8> = (0..100_000_u32).map(|i| (i % 253) as u8).collect();
+        let trees = db.start_transaction(["stream"]).unwrap();
+        let t = trees.get(0);
+        t.put_stream("big", &value[..]).unwrap();
+        t.set("small", b"small".to_vec()).unwrap();
+        trees.commit().unwrap();
+
+        let trees = db.start_transaction(["stream"]).unwrap();
+        let t = trees.get(0);
+        let mut reader = t.get_reader("big").unwrap().unwrap();
+        assert_eq!(reader.len(), value.len() as u64);
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, value);
+        reader.seek(SeekFrom::Start(50_000)).unwrap();
+        let mut buf = [0_u8; 4096];
+        reader.read_exact(&mut buf[..]).unwrap();
+        assert_eq!(buf[..], value[50_000..54_096]);
+        assert!(t.get_reader("missing").unwrap().is_none());
+        let small = t.get("small").unwrap().unwrap();
+        assert_eq!(small, b"small".to_vec());
+        // served from the cache without copying
+        assert_eq!(t.get("small").unwrap().unwrap().as_ptr(), small.as_ptr());
+    }
+
+    #[test]
+    fn test_slow_stream() {
+        struct Gated(Receiver<Vec<u8>>);
+
+        impl Read for Gated {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match self.0.recv_timeout(Duration::from_secs(5)) {
+                    Ok(chunk) => {
+                        buf[..chunk.len()].copy_from_slice(&chunk);
+                        Ok(chunk.len())
+                    }
+                    Err(RecvTimeoutError::Disconnected) => Ok(0),
+                    Err(err) => Err(std::io::Error::other(err)),
+                }
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let options = TransactionOptions {
+            granularity: LockGranularity::Key,
+            ..Default::default()
+        };
+        let (tx, rx) = unbounded();
+        thread::scope(|scope| {
+            let streamer = scope.spawn(|| {
+                let trees = db.start_transaction_with(["s"], options.clone()).unwrap();
+                trees.get(0).put_stream("slow", Gated(rx)).unwrap();
+                trees.commit().unwrap();
+            });
+            tx.send(b"first".to_vec()).unwrap();
+            // writes and commits to the tree go on while the stream waits for its next chunk
+            let trees = db.start_transaction_with(["s"], options.clone()).unwrap();
+            trees.get(0).set("fast", "v").unwrap();
+            trees.commit().unwrap();
+            tx.send(b" second".to_vec()).unwrap();
+            drop(tx);
+            streamer.join().unwrap();
+        });
+        let snapshot = db.read_transaction(["s"]).unwrap();
+        assert_eq!(
+            snapshot.get(0).get("slow").unwrap(),
+            Some("first second".into())
+        );
+        assert_eq!(snapshot.get(0).get("fast").unwrap(), Some("v".into()));
+    }
+
+    #[test]
+    fn test_secondary_index() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        // values look like "<city>:<name>"
+        let city = |_: &[u8], value: &[u8]| value.split(|b| *b == b':').next().map(|x| x.to_vec());
+        db.define_index("by_city", "users", city).unwrap();
+
+        let trees = db.start_transaction(["users"]).unwrap();
+        let users = trees.get(0);
+        users.set("1", "paris:ann").unwrap();
+        users.set("2", "rome:bob").unwrap();
+        users.set("3", "paris:cid").unwrap();
+        let found = trees.lookup_by_index("by_city", "paris").unwrap();
+        assert_eq!(
+            found,
+            vec![
+                ("1".into(), "paris:ann".into()),
+                ("3".into(), "paris:cid".into())
+            ]
+        );
+        let entry = SecondaryIndex::entry_key(b"paris", b"1");
+        let index_tree = trees.tree("by_city.index").unwrap();
+        assert_eq!(index_tree.get(entry).unwrap(), Some(IVec::from("")));
+        users.set("1", "rome:ann").unwrap();
+        users.remove("3").unwrap();
+        trees.commit().unwrap();
+
+        let trees = db.start_transaction(["users"]).unwrap();
+        let found = trees.lookup_by_index("by_city", "paris").unwrap();
+        assert!(found.is_empty());
+        let found = trees.lookup_by_index("by_city", "rome").unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(trees.lookup_by_index("by_name", "ann").is_err());
+        drop(trees);
+
+        let name = |_: &[u8], value: &[u8]| value.split(|b| *b == b':').nth(1).map(|x| x.to_vec());
+        db.define_index("by_name", "users", name).unwrap();
+        db.rebuild_index("by_name").unwrap();
+        let trees = db.start_transaction(["users"]).unwrap();
+        let found = trees.lookup_by_index("by_name", "bob").unwrap();
+        assert_eq!(found, vec![("2".into(), "rome:bob".into())]);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let trees = db.start_transaction(["many"]).unwrap();
+        let t = trees.get(0);
+        for i in 0..200 {
+            t.set(
+                format!("key{i}"),
+                format!("value{i}").repeat(i % 7 * 40 + 1),
+            )
+            .unwrap();
+        }
+        trees.commit().unwrap();
+        drop(trees);
+        drop(db);
+
+        let db = Db::open(dir.path()).unwrap();
+        let trees = db.start_transaction(["many"]).unwrap();
+        let t = trees.get(0);
+        // warm the cache for a few keys
+        t.get("key5").unwrap();
+        t.get("key150").unwrap();
+        let keys = [
+            "key150", "missing", "key3", "key199", "key5", "key0", "key3",
+        ];
+        let values = t.get_many(keys).unwrap();
+        assert_eq!(values.len(), keys.len());
+        for (key, value) in keys.iter().zip(values) {
+            let expected = key[3..].parse::<usize>().ok().map(|i| {
+                let value = format!("value{i}").repeat(i % 7 * 40 + 1);
+                IVec::from(value)
+            });
+            assert_eq!(value, expected);
+        }
+        assert_eq!(t.get_many(["missing"]).unwrap(), vec![None]);
+    }
+
+    #[test]
+    fn test_redo_recovery() {
+        let dir = tempdir().unwrap();
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let trees = db.start_transaction(["a", "b"]).unwrap();
+            trees.get(0).set("k", "v1").unwrap();
+            trees.get(1).set("k", "w1").unwrap();
+            trees.commit().unwrap();
+
+            // log a transaction the way commit does, then stop before publishing anything
+            let trees = db.start_transaction(["a", "b"]).unwrap();
+            trees.get(0).set("k", "v2").unwrap();
+            trees.get(1).remove("k").unwrap();
+            trees.get(1).set("k2", "w2").unwrap();
+            let write_set = WriteSet {
+                trees: trees
+                    .trees
+                    .iter()
+                    .map(|tree| tree.state.writer.lock().publish(tree.name.as_str()))
+                    .collect(),
+                phase: None,
+                options: None,
+            };
+            db.batch
+                .commit(TransactionData {
+                    transaction_id: trees.transaction_id().unwrap(),
+                    data: Some(serde_json::to_vec(&write_set).unwrap()),
+                })
+                .unwrap();
+            trees.committed.store(true, Ordering::SeqCst);
+
+            // never logged, so never visible
+            let trees = db.start_transaction(["c"]).unwrap();
+            trees.get(0).set("k", "lost").unwrap();
+            std::mem::forget(trees);
+        }
+        let db = Db::open(dir.path()).unwrap();
+        let trees = db.start_transaction(["a", "b", "c"]).unwrap();
+        assert_eq!(trees.get(0).get("k").unwrap(), Some("v2".into()));
+        assert_eq!(trees.get(1).get("k").unwrap(), None);
+        assert_eq!(trees.get(1).get("k2").unwrap(), Some("w2".into()));
+        assert_eq!(trees.get(2).get("k").unwrap(), None);
+        trees.get(0).set("k", "v3").unwrap();
+        trees.commit().unwrap();
+        drop(trees);
+        drop(db);
+
+        let db = Db::open(dir.path()).unwrap();
+        let trees = db.start_transaction(["a"]).unwrap();
+        assert_eq!(trees.get(0).get("k").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn test_optimistic() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let optimistic = || {
+            let options = TransactionOptions {
+                mode: TransactionMode::Optimistic,
+                ..Default::default()
+            };
+            db.start_transaction_with(["t"], options).unwrap()
+        };
+        let trees = db.start_transaction(["t"]).unwrap();
+        trees.get(0).set("a", "0").unwrap();
+        trees.get(0).set("b", "0").unwrap();
+        trees.commit().unwrap();
+
+        // disjoint transactions run side by side and both commit
+        let first = optimistic();
+        let second = optimistic();
+        assert_eq!(first.get(0).get("a").unwrap(), Some("0".into()));
+        first.get(0).set("a", "1").unwrap();
+        assert_eq!(second.get(0).get("b").unwrap(), Some("0".into()));
+        second.get(0).set("b", "1").unwrap();
+        first.commit().unwrap();
+        second.commit().unwrap();
+
+        // a key read by the transaction changed under it
+        let stale = optimistic();
+        assert_eq!(stale.get(0).get("a").unwrap(), Some("1".into()));
+        stale.get(0).set("c", "stale").unwrap();
+        let trees = optimistic();
+        trees.get(0).set("a", "2").unwrap();
+        trees.commit().unwrap();
+        assert!(matches!(stale.commit(), Err(Error::Conflict(_))));
+
+        // a key inserted into a scanned range
+        let scan = optimistic();
+        assert_eq!(scan.get(0).keys::<&str, _>("a".."z").unwrap().len(), 2);
+        scan.get(0).set("count", "2").unwrap();
+        let trees = optimistic();
+        trees.get(0).set("d", "0").unwrap();
+        trees.commit().unwrap();
+        assert!(matches!(scan.commit(), Err(Error::Conflict(_))));
+
+        // blind writes never conflict, the last commit wins
+        let first = optimistic();
+        let second = optimistic();
+        first.get(0).set("e", "first").unwrap();
+        second.get(0).set("e", "second").unwrap();
+        second.commit().unwrap();
+        first.commit().unwrap();
+
+        let trees = db.start_transaction(["t"]).unwrap();
+        let t = trees.get(0);
+        assert_eq!(t.get("a").unwrap(), Some("2".into()));
+        assert_eq!(t.get("b").unwrap(), Some("1".into()));
+        assert_eq!(t.get("c").unwrap(), None);
+        assert_eq!(t.get("count").unwrap(), None);
+        assert_eq!(t.get("d").unwrap(), Some("0".into()));
+        assert_eq!(t.get("e").unwrap(), Some("first".into()));
+    }
+
+    #[test]
+    fn test_prune_versions() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let options = TransactionOptions {
+            mode: TransactionMode::Optimistic,
+            ..Default::default()
+        };
+        let versions = || {
+            db.read_transaction(["t"])
+                .unwrap()
+                .get(0)
+                .state
+                .versions
+                .clone()
+        };
+        db.transaction(&["t"], |tx| tx.get(0).set("k", "v"))
+            .unwrap();
+        let reader = db.start_transaction_with(["t"], options).unwrap();
+        assert!(reader.get(0).get("k").unwrap().is_some());
+        db.transaction(&["t"], |tx| tx.get(0).remove("k").map(|_| ()))
+            .unwrap();
+        db.transaction(&["t"], |tx| tx.get(0).set("other", "v"))
+            .unwrap();
+        // the removal stays while the reader may still validate against it
+        assert_eq!(versions().get("k"), Some(&2));
+        reader.get(0).set("copy", "v").unwrap();
+        assert!(matches!(reader.commit(), Err(Error::Conflict(_))));
+        drop(reader);
+        db.transaction(&["t"], |tx| tx.get(0).set("other", "w"))
+            .unwrap();
+        assert_eq!(versions().get("k"), None);
+        assert_eq!(versions().get("other"), Some(&4));
+    }
+
+    #[test]
+    fn test_key_locks() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let options = TransactionOptions {
+            granularity: LockGranularity::Key,
+            ..Default::default()
+        };
+        let key_locked = || db.start_transaction_with(["t"], options.clone()).unwrap();
+        // returns once some transaction waits for a lock `owner` holds
+        let blocked_on = |owner: usize| {
+            while !db
+                .active_transactions()
+                .iter()
+                .any(|status| status.blocked_by.contains(&owner))
+            {
+                thread::yield_now();
+            }
+        };
+        let trees = db.start_transaction(["t"]).unwrap();
+        trees.get(0).set("a", "0").unwrap();
+        trees.get(0).set("b", "0").unwrap();
+        trees.commit().unwrap();
+
+        // writers of different keys share the tree
+        let first = key_locked();
+        let second = key_locked();
+        first.get(0).set("a", "1").unwrap();
+        second.get(0).set("b", "1").unwrap();
+        // `a` is only locked once the first transaction is done with it
+        thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                let trees = key_locked();
+                let value = trees.get(0).get("a").unwrap();
+                trees.commit().unwrap();
+                value
+            });
+            blocked_on(first.owner);
+            assert!(!reader.is_finished());
+            second.commit().unwrap();
+            first.commit().unwrap();
+            assert_eq!(reader.join().unwrap(), Some("1".into()));
+        });
+
+        // a scanned range stays free of phantoms until the scan's transaction is done
+        let scan = key_locked();
+        assert_eq!(scan.get(0).keys::<&str, _>("a".."c").unwrap().len(), 2);
+        thread::scope(|scope| {
+            let writer = scope.spawn(|| {
+                let trees = key_locked();
+                trees.get(0).set("c", "outside").unwrap();
+                trees.get(0).set("ab", "inside").unwrap();
+                trees.commit().unwrap();
+            });
+            blocked_on(scan.owner);
+            assert!(!writer.is_finished());
+            assert_eq!(scan.get(0).keys::<&str, _>("a".."c").unwrap().len(), 2);
+            scan.commit().unwrap();
+        });
+
+        let trees = db.start_transaction(["t"]).unwrap();
+        let t = trees.get(0);
+        assert_eq!(t.keys::<&str, _>(..).unwrap(), vec!["a", "ab", "b", "c"]);
+        assert_eq!(t.get("b").unwrap(), Some("1".into()));
+    }
+
+    #[test]
+    fn test_add_tree() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let tenants: Vec<String> = (0..3).map(|i| format!("tenant{i}")).collect();
+        let mut trees = db.start_transaction(&tenants[1..2]).unwrap();
+        assert_eq!(trees.add_tree(&tenants[2]).unwrap(), 1);
+        assert_eq!(trees.add_tree("tenant1").unwrap(), 0);
+        // out of order, a tree is only added if nobody holds it
+        let holder = db.start_transaction(["tenant0"]).unwrap();
+        assert!(matches!(
+            trees.add_tree(&tenants[0]),
+            Err(Error::LockOrder(name)) if name == "tenant0"
+        ));
+        drop(holder);
+        assert_eq!(trees.add_tree(&tenants[0]).unwrap(), 2);
+        trees.get(1).set("k", "2").unwrap();
+        trees.commit().unwrap();
+
+        // the closure starts over with the tree that could not be added
+        let mut holder = Some(db.start_transaction(["tenant0"]).unwrap());
+        let mut attempts = 0;
+        db.transaction(&["tenant2"], |tx| {
+            attempts += 1;
+            let added = tx.add_tree("tenant0");
+            holder.take();
+            tx.get(added?).set("k", "0")
+        })
+        .unwrap();
+        assert_eq!(attempts, 2);
+
+        // transactions that do not lock whole trees add them in any order
+        let options = TransactionOptions {
+            mode: TransactionMode::Optimistic,
+            ..Default::default()
+        };
+        let mut trees = db.start_transaction_with(["tenant2"], options).unwrap();
+        let idx = trees.add_tree("tenant0").unwrap();
+        assert_eq!(trees.get(idx).get("k").unwrap(), Some("0".into()));
+        trees.get(0).remove("k").unwrap();
+        trees.commit().unwrap();
+
+        let snapshot = db.read_transaction(tenants.iter()).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("0".into()));
+        assert_eq!(snapshot.get(2).get("k").unwrap(), None);
+    }
+
+    #[test]
+    fn test_savepoint() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let mut trees = db.start_transaction(["a"]).unwrap();
+        trees.get(0).set("k1", "1").unwrap();
+        let outer = trees.savepoint();
+        trees.get(0).set("k1", "bad").unwrap();
+        trees.get(0).set("k2", "2").unwrap();
+        let inner = trees.savepoint();
+        let b = trees.add_tree("b").unwrap();
+        trees.get(b).set("k", "b").unwrap();
+        trees.get(0).remove("k2").unwrap();
+
+        trees.rollback_to(inner).unwrap();
+        assert_eq!(trees.get(0).get("k2").unwrap(), Some("2".into()));
+        assert_eq!(trees.get(b).get("k").unwrap(), None);
+        trees.rollback_to(outer).unwrap();
+        assert_eq!(trees.get(0).get("k1").unwrap(), Some("1".into()));
+        assert_eq!(trees.get(0).get("k2").unwrap(), None);
+        assert!(matches!(
+            trees.rollback_to(inner),
+            Err(Error::UnknownSavepoint)
+        ));
+        // the outer savepoint can be rolled back to again
+        trees.get(0).set("k3", "3").unwrap();
+        trees.rollback_to(outer).unwrap();
+        trees.get(b).set("k", "b").unwrap();
+        trees.commit().unwrap();
+
+        let snapshot = db.read_transaction(["a", "b"]).unwrap();
+        let a = snapshot.get(0);
+        let keys: Vec<IVec> = a.iter().map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, vec![IVec::from("k1")]);
+        assert_eq!(a.get("k1").unwrap(), Some("1".into()));
+        assert_eq!(snapshot.get(1).get("k").unwrap(), Some("b".into()));
+
+        // an optimistic transaction goes back to the state it started from, not to later commits
+        let options = TransactionOptions {
+            mode: TransactionMode::Optimistic,
+            ..Default::default()
+        };
+        let optimistic = db.start_transaction_with(["a"], options).unwrap();
+        let savepoint = optimistic.savepoint();
+        optimistic.get(0).set("k1", "mine").unwrap();
+        db.transaction(&["a"], |tx| tx.get(0).set("k1", "theirs"))
+            .unwrap();
+        optimistic.rollback_to(savepoint).unwrap();
+        assert_eq!(optimistic.get(0).get("k1").unwrap(), Some("1".into()));
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        db.defaults.write().lock_timeout = Some(Duration::from_millis(20));
+        let options = TransactionOptions {
+            granularity: LockGranularity::Key,
+            lock_timeout: None,
+            retries: 0,
+            ..Default::default()
+        };
+        let leaked = db.start_transaction_with(["a"], options.clone()).unwrap();
+        leaked.get(0).set("k", "v").unwrap();
+        std::mem::forget(leaked);
+        assert!(matches!(
+            db.start_transaction(["b", "a"]),
+            Err(Error::LockTimeout)
+        ));
+        // nothing stays locked after the failed start
+        db.transaction(&["b"], |tx| tx.get(0).set("k", "v"))
+            .unwrap();
+
+        // two transactions locking keys in opposite order
+        let first = db.start_transaction_with(["b"], options.clone()).unwrap();
+        let second = db.start_transaction_with(["b"], options).unwrap();
+        first.get(0).set("x", "1").unwrap();
+        second.get(0).set("y", "2").unwrap();
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                let result = first.get(0).set("y", "1");
+                first.commit().and(result)
+            });
+            while db.waits.waits_for(first.owner).is_empty() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert!(matches!(second.get(0).set("x", "2"), Err(Error::Deadlock)));
+            second.rollback().unwrap();
+            waiting.join().unwrap().unwrap();
+        });
+        let trees = db.start_transaction(["b"]).unwrap();
+        assert_eq!(trees.get(0).get("y").unwrap(), Some("1".into()));
+    }
+
+    #[test]
+    fn test_tree_lock_deadlock() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let options = TransactionOptions {
+            granularity: LockGranularity::Key,
+            ..Default::default()
+        };
+        let mut holder = db.start_transaction(["t1"]).unwrap();
+        let committer = db.start_transaction_with(["t1", "t2"], options).unwrap();
+        committer.get(1).set("k", "v").unwrap();
+        thread::scope(|scope| {
+            // waits for the tree lock of t1 while holding a key of t2
+            let waiting = scope.spawn(|| committer.commit());
+            while db.waits.waits_for(committer.owner).is_empty() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(db.waits.waits_for(committer.owner), vec![holder.owner]);
+            assert!(matches!(holder.add_tree("t2"), Err(Error::Deadlock)));
+            drop(holder);
+            waiting.join().unwrap().unwrap();
+        });
+        let snapshot = db.read_transaction(["t2"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("v".into()));
+    }
+
+    #[test]
+    fn test_durability() {
+        let dir = tempdir().unwrap();
+        {
+            let db = Db::open(dir.path()).unwrap();
+            db.defaults.write().durability = Durability::Fsync;
+            let trees = db.start_transaction(["synced"]).unwrap();
+            trees.get(0).set("k", "fsync").unwrap();
+            let info = trees.commit().unwrap();
+            assert_eq!(info.durability, Durability::Fsync);
+            assert!(!db.file_manager.created.load(Ordering::SeqCst));
+            // a commit changing few keys of the tree appends a delta of just those keys
+            db.transaction(&["synced"], |tx| {
+                for key in ["a", "b", "c"] {
+                    tx.get(0).set(key, "v")?;
+                }
+                Ok(())
+            })
+            .unwrap();
+            db.transaction(&["synced"], |tx| tx.get(0).set("a", "w"))
+                .unwrap();
+            let snapshot = db.read_transaction(["synced"]).unwrap();
+            let index_log = snapshot.get(0).public.index_log.lock().clone();
+            assert_eq!((index_log.version, index_log.deltas), (Some(3), 1));
+            assert!(index_log.changes.is_empty());
+
+            let options = TransactionOptions {
+                durability: Durability::None,
+                ..Default::default()
+            };
+            let trees = db.start_transaction_with(["lazy"], options).unwrap();
+            trees.get(0).set("k", "none").unwrap();
+            let info = trees.commit().unwrap();
+            assert_eq!(info.durability, Durability::None);
+            // the record is written, the index record follows once the log has caught up
+            let snapshot = db.read_transaction(["lazy"]).unwrap();
+            assert_eq!(snapshot.get(0).get("k").unwrap(), Some("none".into()));
+            let index_log = snapshot.get(0).public.index_log.clone();
+            while index_log.lock().version != Some(1) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        let db = Db::open(dir.path()).unwrap();
+        let snapshot = db.read_transaction(["synced", "lazy"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("fsync".into()));
+        assert_eq!(snapshot.get(1).get("k").unwrap(), Some("none".into()));
+    }
+
+    #[test]
+    fn test_commit_async() {
+        struct Unpark(thread::Thread);
+
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let waker = Waker::from(Arc::new(Unpark(thread::current())));
+            let mut cx = Context::from_waker(&waker);
+            let mut future = pin!(future);
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+                thread::park();
+            }
+        }
+
+        let dir = tempdir().unwrap();
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let trees = db.start_transaction(["async"]).unwrap();
+            trees.get(0).set("k", "first").unwrap();
+            let first = trees.commit_async().unwrap();
+            // the tree is unlocked before the log is done
+            let trees = db.start_transaction(["async"]).unwrap();
+            assert_eq!(trees.get(0).get("k").unwrap(), Some("first".into()));
+            trees.get(0).set("k", "second").unwrap();
+            let second = trees.commit_async().unwrap();
+            let info = block_on(second);
+            assert!(first.is_done());
+            assert_eq!(
+                first.wait().unwrap().transaction_id + 1,
+                info.unwrap().transaction_id
+            );
+            // the index records are written before the handles learn the commits are done
+            let snapshot = db.read_transaction(["async"]).unwrap();
+            let index_log = snapshot.get(0).public.index_log.clone();
+            assert_eq!(index_log.lock().version, Some(2));
+            let trees = db.start_transaction(["async"]).unwrap();
+            trees.get(0).set("k", "third").unwrap();
+            drop(trees.commit_async().unwrap());
+            while index_log.lock().version != Some(3) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        let db = Db::open(dir.path()).unwrap();
+        let snapshot = db.read_transaction(["async"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("third".into()));
+    }
+
+    #[test]
+    fn test_poisoned_tree() {
+        let dir = tempdir().unwrap();
+        {
+            let db = Db::open(dir.path()).unwrap();
+            let trees = db.start_transaction(["poisoned"]).unwrap();
+            trees.get(0).set("k", "v").unwrap();
+            // the index records of the commit cannot be appended to a read-only tree file
+            let public = trees.trees[0].state.public.clone();
+            let path = dir.path().join(public.file_name.as_str());
+            *public.file.write() = std::fs::File::open(path).unwrap();
+            assert!(trees.commit().is_ok());
+            let trees = db.start_transaction(["poisoned"]).unwrap();
+            assert_eq!(trees.get(0).get("k").unwrap(), Some("v".into()));
+            assert!(matches!(
+                trees.get(0).set("k", "w"),
+                Err(Error::Poisoned(_, _))
+            ));
+        }
+        let db = Db::open(dir.path()).unwrap();
+        let snapshot = db.read_transaction(["poisoned"]).unwrap();
+        assert_eq!(snapshot.get(0).get("k").unwrap(), Some("v".into()));
+    }
+
+    #[test]
+    fn test_limits() {
+        let dir = tempdir().unwrap();
+        let db = Db::open(dir.path()).unwrap();
+        let options = TransactionOptions {
+            limits: TransactionLimits {
+                max_writes: Some(3),
+                max_bytes: Some(10),
+                max_memory: None,
+            },
+            ..Default::default()
+        };
+        let trees = db.start_transaction_with(["limited"], options).unwrap();
+        let tree = trees.get(0);
+        tree.set("a", "12345").unwrap();
+        // writing a key again counts its bytes but not another write
+        tree.set("a", "123").unwrap();
+        assert!(matches!(tree.set("b", "123"), Err(Error::LimitExceeded(_))));
+        let savepoint = trees.savepoint();
+        tree.remove("b").unwrap();
+        tree.remove("c").unwrap();
+        assert!(matches!(tree.remove("d"), Err(Error::LimitExceeded(_))));
+        let usage = trees.usage();
+        assert_eq!((usage.writes, usage.bytes), (3, 8));
+        assert!(usage.memory > 0);
+        trees.rollback_to(savepoint).unwrap();
+        assert_eq!(trees.usage().writes, 1);
+        tree.remove("d").unwrap();
+        // streams stop at the limit instead of writing everything first
+        let path = dir.path().join("limited.tree");
+        let len = path.metadata().unwrap().len();
+        let big = vec![0_u8; 4 * PAGE_LEN as usize];
+        assert!(matches!(
+            tree.put_stream("a", &big[..]),
+            Err(Error::LimitExceeded(_))
+        ));
+        assert!(path.metadata().unwrap().len() < len + PAGE_LEN);
+        tree.put_stream("a", &big[..2]).unwrap();
+        assert_eq!(trees.usage().bytes, 10);
+        trees.commit().unwrap();
+
+        // streaming a new key counts as a single write
+        let options = TransactionOptions {
+            limits: TransactionLimits {
+                max_writes: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let trees = db.start_transaction_with(["limited"], options).unwrap();
+        trees.get(0).put_stream("new", &big[..]).unwrap();
+        let usage = trees.usage();
+        assert_eq!((usage.writes, usage.bytes), (1, big.len() as u64));
+        assert!(matches!(
+            trees.get(0).put_stream("other", &big[..1]),
+            Err(Error::LimitExceeded(_))
+        ));
+        trees.commit().unwrap();
     }
 }